@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: MIT
+
+//! Read and write throughput through the FIFO, using registers in memory where every cycle
+//! finishes as soon as it is started, so only the time spent driving the registers is measured.
+//! Writes use a fixed page size, so that they do not include the SFDP cycles that reading it
+//! takes on every [`Spi::write`].
+//!
+//! Run with `cargo bench --features bench`.
+
+use intel_spi::{HsfStsCtl, Spi, SpiRegs};
+use std::{mem, slice, time::{Duration, Instant}};
+
+/// Time spent on each buffer size and operation
+const DURATION: Duration = Duration::from_millis(500);
+
+/// Page size of the flash, passed to writes instead of being read from SFDP
+const PAGE_SIZE: usize = 256;
+
+/// Buffer sizes to measure, from a single FIFO chunk to a 64 KiB sector
+const SIZES: [usize; 4] = [64, 256, 4096, 65536];
+
+/// Run `f` repeatedly for `DURATION`, returning the 64-byte chunks per second it moved
+fn measure(size: usize, mut f: impl FnMut()) -> f64 {
+    let start = Instant::now();
+    let mut iterations = 0;
+    while start.elapsed() < DURATION {
+        f();
+        iterations += 1;
+    }
+    let chunks = ((size + 63) / 64) * iterations;
+    chunks as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let mut storage = vec![0u32; (mem::size_of::<SpiRegs>() + 3) / 4];
+    let bytes = unsafe {
+        slice::from_raw_parts_mut(storage.as_mut_ptr() as *mut u8, storage.len() * 4)
+    };
+    let regs = SpiRegs::from_bytes_mut(bytes).expect("register bank is not aligned");
+    regs.set_hsfsts_ctl(HsfStsCtl::FDONE);
+
+    println!("{:>8} {:>16} {:>16}", "size", "read chunks/s", "write chunks/s");
+    for &size in SIZES.iter() {
+        let mut buf = vec![0; size];
+        let read = measure(size, || {
+            regs.read(0x10000, &mut buf).expect("read failed");
+        });
+        let write = measure(size, || {
+            regs.write_cancellable(0x10000, &buf, PAGE_SIZE, &|| false).expect("write failed");
+        });
+        println!("{:>8} {:>16.0} {:>16.0}", size, read, write);
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MIT
+
+//! Write an image to the start of the mock flash, sector by sector, and read it back.
+//!
+//! This erases whatever the image covers, so it only runs against the in-memory flash of the
+//! `mock` feature: `cargo run --features mock --example flash -- image.rom`
+
+extern crate libc;
+extern crate intel_spi;
+
+use intel_spi::{Logger, Phase, Spi, SpiError};
+use std::{env, fs};
+
+mod util;
+
+/// Erase and write `image` at the start of the flash one 4 KiB sector at a time, then read it
+/// back and compare
+fn flash<S: Spi>(spi: &mut S, image: &[u8], logger: &mut dyn Logger) -> Result<(), SpiError> {
+    if image.len() > spi.len()? {
+        return Err(SpiError::OutOfRange);
+    }
+
+    for (i, sector) in image.chunks(4096).enumerate() {
+        let address = i * 4096;
+        spi.erase(address)?;
+        let mut count = 0;
+        while count < sector.len() {
+            match spi.write(address + count, &sector[count..])? {
+                0 => return Err(SpiError::OutOfRange),
+                written => count += written,
+            }
+        }
+        logger.progress(Phase::Write, address + sector.len(), image.len());
+    }
+    logger.finish(Phase::Write);
+
+    let mut data = vec![0; image.len()];
+    for (i, chunk) in data.chunks_mut(65536).enumerate() {
+        spi.read_exact(i * 65536, chunk)?;
+        logger.progress(Phase::Verify, i * 65536 + chunk.len(), image.len());
+    }
+    logger.finish(Phase::Verify);
+
+    match data.iter().zip(image.iter()).position(|(a, b)| a != b) {
+        Some(i) => Err(SpiError::Mismatch(i)),
+        None => Ok(()),
+    }
+}
+
+#[cfg_attr(test, allow(dead_code))]
+fn main() {
+    let path = env::args().nth(1).expect("usage: flash <image>");
+    let image = fs::read(path).expect("failed to read image");
+
+    let mut spi = util::get_mock_spi();
+    let mut logger = util::StderrLogger::new();
+
+    flash(&mut spi, &image, &mut logger).unwrap();
+    logger.message(format_args!("SPI flashed {} KB", image.len() / 1024));
+}
+
+#[cfg(test)]
+mod tests {
+    use intel_spi::{MockSpi, NullLogger, Spi, SpiError};
+
+    use super::flash;
+
+    #[test]
+    fn mock() {
+        let mut spi = MockSpi::with_image(vec![0; 1024 * 1024]);
+        let image: Vec<u8> = (0..300_000).map(|i| (i * 7 + i / 4096) as u8).collect();
+        flash(&mut spi, &image, &mut NullLogger).unwrap();
+        assert!(spi.flash().data[..image.len()] == image[..]);
+        // The rest of the last sector was erased, and nothing after it
+        let end = (image.len() + 4095) / 4096 * 4096;
+        assert!(spi.flash().data[image.len()..end].iter().all(|&byte| byte == 0xFF));
+        assert!(spi.flash().data[end..].iter().all(|&byte| byte == 0));
+
+        // A bit that does not erase is caught by the read back
+        spi.flash().stuck = Some((0x1_2345, 0x01));
+        assert_eq!(flash(&mut spi, &image, &mut NullLogger), Err(SpiError::Mismatch(0x1_2345)));
+
+        let len = spi.len().unwrap();
+        assert_eq!(flash(&mut spi, &vec![0; len + 1], &mut NullLogger), Err(SpiError::OutOfRange));
+    }
+}
@@ -3,28 +3,54 @@
 extern crate libc;
 extern crate intel_spi;
 
-use intel_spi::Spi;
+use intel_spi::{Logger, Phase, Spi};
 use std::fs;
 
 mod util;
 
+/// Read the whole flash, 64 KiB at a time
+fn read<S: Spi>(spi: &mut S, logger: &mut dyn Logger) -> Vec<u8> {
+    let len = spi.len().unwrap();
+    logger.message(format_args!("SPI ROM: {} KB", len / 1024));
+
+    let mut data: Vec<u8> = Vec::with_capacity(len);
+    while data.len() < len {
+        let address = data.len();
+        let buf = &mut data.spare_capacity_mut()[..65536.min(len - address)];
+        let read = spi.read_uninit(address, buf).unwrap();
+        unsafe { data.set_len(address + read) };
+        logger.progress(Phase::Read, data.len(), len);
+    }
+    logger.finish(Phase::Read);
+    data
+}
+
+#[cfg_attr(test, allow(dead_code))]
 fn main() {
+    #[cfg(feature = "mock")]
+    let mut spi = util::get_mock_spi();
+    #[cfg(not(feature = "mock"))]
     let mut spi = unsafe { util::get_spi() };
 
+    #[cfg(not(feature = "mock"))]
     eprintln!("SPI HSFSTS_CTL: {:?}", spi.regs.hsfsts_ctl());
 
-    let len = spi.len().unwrap();
-    eprintln!("SPI ROM: {} KB", len / 1024);
+    let mut logger = util::StderrLogger::new();
+    let data = read(&mut spi, &mut logger);
 
-    let mut data = Vec::with_capacity(len);
-    while data.len() < len {
-        let mut buf = [0; 65536];
-        let read = spi.read(data.len(), &mut buf).unwrap();
-        data.extend_from_slice(&buf[..read]);
-        eprint!("\rSPI READ: {} KB", data.len() / 1024);
-    }
+    fs::write("read.rom", data).unwrap();
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use intel_spi::{MockSpi, NullLogger};
 
-    eprintln!();
+    use super::read;
 
-    fs::write("read.rom", &data).unwrap();
+    #[test]
+    fn mock() {
+        let image: Vec<u8> = (0..1024 * 1024 + 100).map(|i| (i * 13 + i / 65536) as u8).collect();
+        let mut spi = MockSpi::with_image(image.clone());
+        assert!(read(&mut spi, &mut NullLogger) == image);
+    }
 }
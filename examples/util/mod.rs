@@ -1,8 +1,10 @@
 // SPDX-License-Identifier: MIT
 
-use intel_spi::{Mapper, SpiDev, PhysicalAddress, VirtualAddress};
+use intel_spi::{Logger, Mapper, Phase, SpiDev, PhysicalAddress, VirtualAddress};
+#[cfg(feature = "mock")]
+use intel_spi::MockSpi;
 
-use std::{fs, ptr};
+use std::{fmt, fs, ptr};
 
 pub struct LinuxMapper;
 
@@ -48,8 +50,60 @@ impl Mapper for LinuxMapper {
     }
 }
 
+#[cfg_attr(feature = "mock", allow(dead_code))]
 pub unsafe fn get_spi() -> SpiDev<'static, LinuxMapper> {
     static mut LINUX_MAPPER: LinuxMapper = LinuxMapper;
     let mcfg = fs::read("/sys/firmware/acpi/tables/MCFG").expect("failed to read MCFG");
-    SpiDev::new(&mcfg, &mut LINUX_MAPPER).expect("failed to get SPI device")
+    SpiDev::try_new(&mcfg, &mut LINUX_MAPPER).unwrap_or_else(|err| panic!("failed to get SPI device: {}", err))
+}
+
+/// Flash emulated in memory instead of hardware, for running without `/dev/mem`. It contains
+/// the file in `INTEL_SPI_MOCK_IMAGE`, or 16 MiB of erased flash if it is not set.
+#[cfg(feature = "mock")]
+#[allow(dead_code)]
+pub fn get_mock_spi() -> MockSpi {
+    let data = match std::env::var_os("INTEL_SPI_MOCK_IMAGE") {
+        Some(path) => fs::read(path).expect("failed to read mock image"),
+        None => vec![0xFF; 16 * 1024 * 1024],
+    };
+    MockSpi::with_image(data)
+}
+
+/// Logs to stderr, printing progress once per megabyte
+pub struct StderrLogger {
+    print_mb: usize,
+}
+
+impl StderrLogger {
+    pub fn new() -> Self {
+        // Invalid number to force first print
+        Self { print_mb: !0 }
+    }
+}
+
+impl Logger for StderrLogger {
+    fn progress(&mut self, phase: Phase, done: usize, _total: usize) {
+        let mb = done / (1024 * 1024);
+        if mb != self.print_mb {
+            eprint!("\rSPI {}: {} MB", phase, mb);
+            self.print_mb = mb;
+        }
+    }
+
+    fn finish(&mut self, _phase: Phase) {
+        eprintln!();
+        self.print_mb = !0;
+    }
+
+    fn message(&mut self, args: fmt::Arguments) {
+        eprintln!("{}", args);
+    }
+
+    fn warn(&mut self, args: fmt::Arguments) {
+        eprintln!("warning: {}", args);
+    }
+
+    fn error(&mut self, args: fmt::Arguments) {
+        eprintln!("error: {}", args);
+    }
 }
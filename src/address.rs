@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: MIT
+
+/// Byte address in the flash, as opposed to an offset in a buffer or a byte count
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FlashAddress(pub usize);
+
+impl From<usize> for FlashAddress {
+    fn from(address: usize) -> Self {
+        Self(address)
+    }
+}
+
+impl From<FlashAddress> for usize {
+    fn from(address: FlashAddress) -> Self {
+        address.0
+    }
+}
@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: MIT
+
+/// Density of one flash component, as encoded in the descriptor component section
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(u8)]
+pub enum ComponentDensity {
+    /// 512 KiB, or 4 Mbit
+    Kib512 = 0b000,
+    /// 1 MiB, or 8 Mbit
+    Mib1 = 0b001,
+    /// 2 MiB, or 16 Mbit
+    Mib2 = 0b010,
+    /// 4 MiB, or 32 Mbit
+    Mib4 = 0b011,
+    /// 8 MiB, or 64 Mbit
+    Mib8 = 0b100,
+    /// 16 MiB, or 128 Mbit
+    Mib16 = 0b101,
+    /// 32 MiB, or 256 Mbit
+    Mib32 = 0b110,
+    /// 64 MiB, or 512 Mbit
+    Mib64 = 0b111,
+}
+
+impl ComponentDensity {
+    /// Decode the low three bits of a density field
+    pub fn new(value: u32) -> Self {
+        match value & 0b111 {
+            0b000 => Self::Kib512,
+            0b001 => Self::Mib1,
+            0b010 => Self::Mib2,
+            0b011 => Self::Mib4,
+            0b100 => Self::Mib8,
+            0b101 => Self::Mib16,
+            0b110 => Self::Mib32,
+            _ => Self::Mib64,
+        }
+    }
+
+    /// Size in bytes
+    pub fn bytes(self) -> usize {
+        (512 * 1024) << (self as u8)
+    }
+
+    /// Size in megabits, as flash parts are usually marketed
+    pub fn megabits(self) -> usize {
+        4 << (self as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ComponentDensity;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(ComponentDensity::new(0b000).bytes(), 512 * 1024);
+        assert_eq!(ComponentDensity::new(0b101).bytes(), 16 * 1024 * 1024);
+        assert_eq!(ComponentDensity::new(0b101).megabits(), 128);
+        assert_eq!(ComponentDensity::new(0b1111), ComponentDensity::Mib64);
+        assert_eq!(ComponentDensity::Mib64.megabits(), 512);
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT
+
+use super::{base_limit, read_u32_le, RegionKind};
+
+/// Descriptor signature, at offset 0x10 of a flash image
+pub(crate) const SIGNATURE: u32 = 0x0FF0_A55A;
+
+/// Layout version of a flash descriptor, told apart like ifdtool does by the read clock
+/// frequency in FLCOMP, as the descriptor has no version field
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DescriptorVersion {
+    /// Version 1, with a 20 MHz read clock, used before Skylake
+    V1,
+    /// Version 2, with a 17 MHz or 30/50 MHz read clock, used from Skylake on
+    V2,
+}
+
+impl DescriptorVersion {
+    /// Version from the FLCOMP value at the start of the component section, or `None` if the
+    /// read clock frequency is not one used by a known version
+    pub fn from_flcomp(flcomp: u32) -> Option<Self> {
+        match (flcomp >> 17) & 0b111 {
+            0b000 => Some(Self::V1),
+            0b100 | 0b110 => Some(Self::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Flash Descriptor Map, as read from the FLMAP0 and FLMAP1 descriptor fields
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DescriptorMap {
+    /// Flash Component Base Address, as a byte offset in the descriptor
+    pub component_base: usize,
+    /// Number of Components
+    pub components: usize,
+    /// Flash Region Base Address, as a byte offset in the descriptor
+    pub region_base: usize,
+    /// Number of Regions
+    pub regions: usize,
+    /// Flash Master Base Address, as a byte offset in the descriptor
+    pub master_base: usize,
+    /// Number of Masters
+    pub masters: usize,
+    /// PCH Strap Base Address, as a byte offset in the descriptor
+    pub pch_strap_base: usize,
+    /// PCH Strap Length, in dwords
+    pub pch_strap_len: usize,
+}
+
+impl DescriptorMap {
+    /// Decode from the raw FLMAP0 and FLMAP1 values
+    pub fn new(flmap0: u32, flmap1: u32) -> Self {
+        Self {
+            component_base: ((flmap0 & 0xFF) as usize) << 4,
+            components: (((flmap0 >> 8) & 0b11) as usize) + 1,
+            region_base: (((flmap0 >> 16) & 0xFF) as usize) << 4,
+            regions: ((flmap0 >> 24) & 0b111) as usize,
+            master_base: ((flmap1 & 0xFF) as usize) << 4,
+            masters: ((flmap1 >> 8) & 0b111) as usize,
+            pch_strap_base: (((flmap1 >> 16) & 0xFF) as usize) << 4,
+            pch_strap_len: (flmap1 >> 24) as usize,
+        }
+    }
+}
+
+/// Base and limit of each region in the descriptor at the start of `image`, indexed by
+/// [`RegionKind::index`], or `None` if the image does not start with a descriptor
+pub fn image_regions(image: &[u8]) -> Option<[Option<(usize, usize)>; 6]> {
+    if read_u32_le(image, 0x10)? != SIGNATURE {
+        return None;
+    }
+    let map = DescriptorMap::new(read_u32_le(image, 0x14)?, read_u32_le(image, 0x18)?);
+    let mut regions = [None; 6];
+    for region in RegionKind::ALL.iter() {
+        regions[region.index()] = read_u32_le(image, map.region_base + region.index() * 4)
+            .and_then(base_limit);
+    }
+    Some(regions)
+}
+
+/// Region whose base or limit differs between two images, from [`check_region_layout`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RegionMismatch {
+    pub region: RegionKind,
+    /// Base and limit in the old image, or `None` if it is unused or there is no descriptor
+    pub old: Option<(usize, usize)>,
+    /// Base and limit in the new image, or `None` if it is unused or there is no descriptor
+    pub new: Option<(usize, usize)>,
+}
+
+/// Check that each of `regions` has the same base and limit in the descriptors of `old` and
+/// `new`, returning the first that does not. Data copied between the images at the offsets of
+/// one would land in the wrong place in the other if the layout changed.
+pub fn check_region_layout(old: &[u8], new: &[u8], regions: &[RegionKind]) -> Result<(), RegionMismatch> {
+    let old_regions = image_regions(old).unwrap_or([None; 6]);
+    let new_regions = image_regions(new).unwrap_or([None; 6]);
+    for &region in regions {
+        let old = old_regions[region.index()];
+        let new = new_regions[region.index()];
+        if old != new {
+            return Err(RegionMismatch { region, old, new });
+        }
+    }
+    Ok(())
+}
+
+/// Flash masters with an entry in the descriptor master section
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Master {
+    /// Host CPU/BIOS (FLMSTR1)
+    Host = 0,
+    /// Management Engine (FLMSTR2)
+    Me = 1,
+    /// Gigabit Ethernet (FLMSTR3)
+    GbE = 2,
+}
+
+/// Region access granted to a master, with one bit per region index
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RegionAccess {
+    /// Master Region Read Access
+    pub read: u16,
+    /// Master Region Write Access
+    pub write: u16,
+}
+
+impl RegionAccess {
+    /// Decode from a raw FLMSTR value
+    pub fn new(flmstr: u32) -> Self {
+        Self {
+            read: ((flmstr >> 8) & 0xFFF) as u16,
+            write: ((flmstr >> 20) & 0xFFF) as u16,
+        }
+    }
+
+    pub fn can_read(&self, region: RegionKind) -> bool {
+        self.read & (1 << region.index()) != 0
+    }
+
+    pub fn can_write(&self, region: RegionKind) -> bool {
+        self.write & (1 << region.index()) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RegionKind;
+    use super::{check_region_layout, DescriptorVersion, RegionMismatch};
+
+    /// Image with a descriptor region in the first 4 KiB and a BIOS region from `bios_base`
+    fn image(bios_base: u32) -> [u8; 0x100] {
+        let mut image = [0xFF; 0x100];
+        image[0x10..0x14].copy_from_slice(&0x0FF0_A55A_u32.to_le_bytes());
+        // FRBA at 0x40
+        image[0x14..0x18].copy_from_slice(&0x0004_0000_u32.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(&0_u32.to_le_bytes());
+        image[0x44..0x48].copy_from_slice(&(0x0FFF_0000 | bios_base).to_le_bytes());
+        for region in 2..6 {
+            image[0x40 + region * 4..0x44 + region * 4].copy_from_slice(&0x0000_7FFF_u32.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(DescriptorVersion::from_flcomp(0x0000_0000), Some(DescriptorVersion::V1));
+        // 17 MHz read clock
+        assert_eq!(DescriptorVersion::from_flcomp(0x3024_0000 | 0b110 << 17), Some(DescriptorVersion::V2));
+        // 50/30 MHz read clock
+        assert_eq!(DescriptorVersion::from_flcomp(0b100 << 17), Some(DescriptorVersion::V2));
+        assert_eq!(DescriptorVersion::from_flcomp(0b001 << 17), None);
+    }
+
+    #[test]
+    fn layout() {
+        let regions = [RegionKind::Ethernet, RegionKind::Bios];
+        assert_eq!(check_region_layout(&image(0x200), &image(0x200), &regions), Ok(()));
+        assert_eq!(check_region_layout(&image(0x200), &image(0x300), &regions), Err(RegionMismatch {
+            region: RegionKind::Bios,
+            old: Some((0x20_0000, 0xFF_FFFF)),
+            new: Some((0x30_0000, 0xFF_FFFF)),
+        }));
+        // Only the given regions are compared
+        assert_eq!(check_region_layout(&image(0x200), &image(0x300), &[RegionKind::Ethernet]), Ok(()));
+
+        // Without a descriptor, every region is unused
+        assert_eq!(check_region_layout(&[0xFF; 0x100], &image(0x200), &regions), Err(RegionMismatch {
+            region: RegionKind::Bios,
+            old: None,
+            new: Some((0x20_0000, 0xFF_FFFF)),
+        }));
+    }
+}
@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: MIT
+
+use core::cmp;
+
+use super::{image_regions, RegionKind};
+
+/// What needs to be done to a sector to turn the old contents into the new contents
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SectorAction {
+    /// Contents already match
+    Skip,
+    /// New contents are erased, so the sector only needs to be erased
+    Erase,
+    /// Sector needs to be erased and then written
+    EraseWrite,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SectorPlan {
+    /// Address of the sector
+    pub address: usize,
+    /// Length of the sector, which is shorter than the sector size for a trailing partial sector
+    pub len: usize,
+    pub action: SectorAction,
+}
+
+/// Compare `old` and `new` one sector at a time, returning what needs to be done to each sector.
+/// New sectors where every byte is `erase_byte`, which is [`ERASED_BYTE`](crate::ERASED_BYTE)
+/// for SPI NOR flash, only need to be erased.
+///
+/// Sectors are produced for the length of `new`. If `old` is shorter, the sectors it does not
+/// fully cover are never skipped. If `old` is longer, the extra data is ignored.
+///
+/// Panics if `sector_size` is zero.
+pub fn sector_diff<'a>(old: &'a [u8], new: &'a [u8], sector_size: usize, erase_byte: u8) -> impl Iterator<Item = SectorPlan> + 'a {
+    new.chunks(sector_size).enumerate().map(move |(i, new_chunk)| {
+        let address = i * sector_size;
+        let old_chunk = old.get(address..cmp::min(address + new_chunk.len(), old.len())).unwrap_or(&[]);
+        let action = if old_chunk == new_chunk {
+            SectorAction::Skip
+        } else if new_chunk.iter().all(|&byte| byte == erase_byte) {
+            SectorAction::Erase
+        } else {
+            SectorAction::EraseWrite
+        };
+        SectorPlan {
+            address,
+            len: new_chunk.len(),
+            action,
+        }
+    })
+}
+
+/// Number of sectors for each action
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SectorCounts {
+    pub skip: usize,
+    pub erase: usize,
+    pub erase_write: usize,
+}
+
+impl SectorCounts {
+    /// Total number of sectors
+    pub fn total(&self) -> usize {
+        self.skip + self.erase + self.erase_write
+    }
+
+    /// Number of sectors that will be changed
+    pub fn changed(&self) -> usize {
+        self.erase + self.erase_write
+    }
+
+    pub(crate) fn add(&mut self, action: SectorAction) {
+        match action {
+            SectorAction::Skip => self.skip += 1,
+            SectorAction::Erase => self.erase += 1,
+            SectorAction::EraseWrite => self.erase_write += 1,
+        }
+    }
+}
+
+/// Summary of the work needed to flash an image, from [`plan_flash`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FlashPlan {
+    /// Sectors across the whole image
+    pub sectors: SectorCounts,
+    /// Sectors starting in each region of the new image's descriptor, indexed by
+    /// [`RegionKind::index`], or `None` if the region is unused or there is no descriptor
+    pub regions: [Option<SectorCounts>; 6],
+}
+
+impl FlashPlan {
+    /// Sectors starting in a region, or `None` if the region is unused
+    pub fn region(&self, region: RegionKind) -> Option<SectorCounts> {
+        self.regions[region.index()]
+    }
+}
+
+/// Summarize the [`sector_diff`] of `chip_data` and `new_image`, with a breakdown by the
+/// regions in the descriptor of `new_image`.
+///
+/// Panics if `sector_size` is zero.
+pub fn plan_flash(chip_data: &[u8], new_image: &[u8], sector_size: usize, erase_byte: u8) -> FlashPlan {
+    let bounds = image_regions(new_image).unwrap_or([None; 6]);
+    let mut plan = FlashPlan {
+        sectors: SectorCounts::default(),
+        regions: [None; 6],
+    };
+    for (counts, bound) in plan.regions.iter_mut().zip(bounds.iter()) {
+        if bound.is_some() {
+            *counts = Some(SectorCounts::default());
+        }
+    }
+    for sector in sector_diff(chip_data, new_image, sector_size, erase_byte) {
+        plan.sectors.add(sector.action);
+        for (counts, bound) in plan.regions.iter_mut().zip(bounds.iter()) {
+            if let (Some(counts), Some((base, limit))) = (counts, bound) {
+                if sector.address >= *base && sector.address <= *limit {
+                    counts.add(sector.action);
+                }
+            }
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{RegionKind, ERASED_BYTE};
+    use super::{plan_flash, sector_diff, SectorAction, SectorCounts};
+
+    #[test]
+    fn actions() {
+        let old = [0x00, 0x00, 0x11, 0x11, 0x22, 0x22];
+        let new = [0x00, 0x00, 0xFF, 0xFF, 0x22, 0x33];
+        let mut plans = sector_diff(&old, &new, 2, ERASED_BYTE);
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::Skip));
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::Erase));
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::EraseWrite));
+        assert_eq!(plans.next(), None);
+    }
+
+    #[test]
+    fn erase_byte() {
+        // A flash that erases to zeros only needs zeroed sectors erased
+        let old = [0x11, 0x11, 0x22, 0x22];
+        let new = [0x00, 0x00, 0xFF, 0xFF];
+        let mut plans = sector_diff(&old, &new, 2, 0x00);
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::Erase));
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::EraseWrite));
+        assert_eq!(plans.next(), None);
+    }
+
+    #[test]
+    fn partial_sector() {
+        let old = [0x00, 0x00, 0x00];
+        let new = [0x00, 0x00, 0x11];
+        let plan = sector_diff(&old, &new, 2, ERASED_BYTE).last().unwrap();
+        assert_eq!((plan.address, plan.len, plan.action), (2, 1, SectorAction::EraseWrite));
+    }
+
+    #[test]
+    fn length_mismatch() {
+        // Old data ends partway through the second sector
+        let old = [0x00, 0x00, 0x00];
+        let new = [0x00, 0x00, 0x00, 0x00];
+        let mut plans = sector_diff(&old, &new, 2, ERASED_BYTE);
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::Skip));
+        assert_eq!(plans.next().map(|x| x.action), Some(SectorAction::EraseWrite));
+        assert_eq!(plans.next(), None);
+
+        // Extra old data is ignored
+        let old = [0x00, 0x00, 0x11, 0x11];
+        let new = [0x00, 0x00];
+        assert_eq!(sector_diff(&old, &new, 2, ERASED_BYTE).count(), 1);
+    }
+
+    #[test]
+    fn plan() {
+        // Descriptor with FRBA at 0x40, descriptor region in the first sector and BIOS region in
+        // the other three, and the ME and other regions unused
+        let mut new = [0xFF; 0x4000];
+        new[0x10..0x14].copy_from_slice(&0x0FF0_A55A_u32.to_le_bytes());
+        new[0x14..0x18].copy_from_slice(&0x0004_0000_u32.to_le_bytes());
+        new[0x18..0x1C].copy_from_slice(&0_u32.to_le_bytes());
+        new[0x40..0x44].copy_from_slice(&0x0000_0000_u32.to_le_bytes());
+        new[0x44..0x48].copy_from_slice(&0x0003_0001_u32.to_le_bytes());
+        for region in 2..6 {
+            new[0x40 + region * 4..0x44 + region * 4].copy_from_slice(&0x0000_7FFF_u32.to_le_bytes());
+        }
+        new[0x2000] = 0x00;
+
+        let mut old = new;
+        old[0x1000] = 0x00;
+        old[0x2000] = 0x11;
+
+        let plan = plan_flash(&old, &new, 0x1000, ERASED_BYTE);
+        assert_eq!(plan.sectors, SectorCounts { skip: 2, erase: 1, erase_write: 1 });
+        assert_eq!(plan.sectors.total(), 4);
+        assert_eq!(plan.region(RegionKind::Descriptor), Some(SectorCounts { skip: 1, erase: 0, erase_write: 0 }));
+        assert_eq!(plan.region(RegionKind::Bios), Some(SectorCounts { skip: 1, erase: 1, erase_write: 1 }));
+        assert_eq!(plan.region(RegionKind::Me), None);
+
+        // Without a descriptor there is no breakdown
+        let plan = plan_flash(&[0; 4], &[0xFF; 4], 2, ERASED_BYTE);
+        assert_eq!(plan.sectors.changed(), 2);
+        assert_eq!(plan.regions, [None; 6]);
+    }
+}
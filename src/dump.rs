@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: MIT
+
+use core::fmt;
+
+use super::{Io, Mmio, SpiRegs};
+
+/// Write a labeled hex dump of the named controller registers, one per line
+pub fn dump_registers(regs: &SpiRegs, out: &mut dyn fmt::Write) -> fmt::Result {
+    let base = regs as *const SpiRegs as usize;
+    let mut line = |name: &str, index: Option<usize>, reg: &Mmio<u32>| -> fmt::Result {
+        let offset = reg.as_ptr() as usize - base;
+        write!(out, "{:#05X} {}", offset, name)?;
+        let mut width = name.len();
+        if let Some(index) = index {
+            write!(out, "{}", index)?;
+            width += if index < 10 { 1 } else { 2 };
+        }
+        writeln!(out, "{:pad$} {:#010X}", "", reg.read(), pad = 12usize.saturating_sub(width))
+    };
+
+    line("BFPREG", None, &regs.bfpreg)?;
+    line("HSFSTS_CTL", None, &regs.hsfsts_ctl)?;
+    line("FADDR", None, &regs.faddr)?;
+    line("DLOCK", None, &regs.dlock)?;
+    for (i, reg) in regs.fdata.iter().enumerate() {
+        line("FDATA", Some(i), reg)?;
+    }
+    line("FRACC", None, &regs.fracc)?;
+    for (i, reg) in regs.freg.iter().enumerate() {
+        line("FREG", Some(i), reg)?;
+    }
+    for (i, reg) in regs.fpr.iter().enumerate() {
+        line("FPR", Some(i), reg)?;
+    }
+    line("GPR", None, &regs.gpr)?;
+    line("SFRACC", None, &regs.sfracc)?;
+    line("FDOC", None, &regs.fdoc)?;
+    line("FDOD", None, &regs.fdod)?;
+    line("AFC", None, &regs.afc)?;
+    line("VSCC0", None, &regs.vscc0)?;
+    line("VSCC1", None, &regs.vscc1)?;
+    line("PTINX", None, &regs.ptinx)?;
+    line("PTDATA", None, &regs.ptdata)?;
+    line("SBRS", None, &regs.sbrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::super::{Io, SpiRegs};
+    use super::dump_registers;
+
+    #[test]
+    fn dump() {
+        let mut regs: SpiRegs = unsafe { mem::zeroed() };
+        regs.bfpreg.write(0x0FFF_0100);
+        regs.fdata[10].write(0xDEAD_BEEF);
+        regs.vscc0.write(0xA080_2005);
+        regs.sbrs.write(1);
+
+        let mut out = String::new();
+        dump_registers(&regs, &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 15 + regs.fdata.len() + regs.freg.len() + regs.fpr.len());
+
+        assert_eq!(lines[0], "0x000 BFPREG       0x0FFF0100");
+        assert_eq!(lines[1], "0x004 HSFSTS_CTL   0x00000000");
+        assert!(lines.contains(&"0x010 FDATA0       0x00000000"));
+        assert!(lines.contains(&"0x038 FDATA10      0xDEADBEEF"));
+        assert!(lines.contains(&"0x0C4 VSCC0        0xA0802005"));
+        assert!(lines.last().unwrap().ends_with(" SBRS         0x00000001"));
+
+        // Values line up whatever the length of the label
+        for line in &lines {
+            assert_eq!(line.len(), 29, "{:?}", line);
+            assert_eq!(&line[19..21], "0x", "{:?}", line);
+        }
+    }
+}
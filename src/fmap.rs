@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT
+
+use coreboot_fs::Rom;
+use std::vec::Vec;
+
+/// Outcome of preserving one FMAP area with [`preserve_fmap_areas`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AreaCopyResult {
+    /// Area was copied from the old image to the new image
+    Copied {
+        old_offset: usize,
+        new_offset: usize,
+        size: usize,
+    },
+    /// Neither image has the area
+    AbsentInBoth,
+    /// Only the new image has the area, so it is left as is
+    AbsentInOld,
+    /// Only the old image has the area, so it is not preserved
+    AbsentInNew,
+    /// Area sizes differ, so it cannot be copied
+    SizeMismatch { old: usize, new: usize },
+    /// Area extends past the end of its image
+    OutOfBounds,
+}
+
+/// Find an FMAP area by name, returning its offset and size
+fn find_area(data: &[u8], name: &str) -> Option<(usize, usize)> {
+    let rom = Rom::new(data);
+    let fmap = rom.fmap()?;
+    (0..fmap.nareas).map(|i| fmap.area(i)).find(|area| area_name(&area.name) == name.as_bytes()).map(|area| {
+        (area.offset as usize, area.size as usize)
+    })
+}
+
+/// Name of an FMAP area, up to the first null
+fn area_name(name: &[u8; 32]) -> &[u8] {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    &name[..len]
+}
+
+/// Copy the FMAP areas in `names` from the `old` image to the `new` image, returning the outcome
+/// for each name in order.
+///
+/// Areas are only copied if both images have them with the same size. Preserving areas such as
+/// `RW_MRC_CACHE` or `SMMSTORE` across an update can be dangerous if their format changed, so
+/// only areas known to be compatible should be listed.
+pub fn preserve_fmap_areas(old: &[u8], new: &mut [u8], names: &[&str]) -> Vec<AreaCopyResult> {
+    names.iter().map(|name| {
+        let (old_offset, old_size, new_offset, new_size) = match (find_area(old, name), find_area(new, name)) {
+            (Some((old_offset, old_size)), Some((new_offset, new_size))) => (old_offset, old_size, new_offset, new_size),
+            (None, None) => return AreaCopyResult::AbsentInBoth,
+            (None, Some(_)) => return AreaCopyResult::AbsentInOld,
+            (Some(_), None) => return AreaCopyResult::AbsentInNew,
+        };
+
+        if old_size != new_size {
+            return AreaCopyResult::SizeMismatch { old: old_size, new: new_size };
+        }
+
+        let old_slice = old.get(old_offset..old_offset + old_size);
+        let new_slice = new.get_mut(new_offset..new_offset + new_size);
+        match (old_slice, new_slice) {
+            (Some(old_slice), Some(new_slice)) => {
+                new_slice.copy_from_slice(old_slice);
+                AreaCopyResult::Copied { old_offset, new_offset, size: new_size }
+            },
+            _ => AreaCopyResult::OutOfBounds,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::{preserve_fmap_areas, AreaCopyResult};
+
+    /// Build an image of `len` bytes filled with `fill`, with an FMAP at the start
+    fn image(len: usize, fill: u8, areas: &[(&str, u32, u32)]) -> Vec<u8> {
+        let mut data = vec![fill; len];
+        let mut fmap = Vec::new();
+        fmap.extend_from_slice(b"__FMAP__");
+        fmap.extend_from_slice(&[1, 1]);
+        fmap.extend_from_slice(&0u64.to_le_bytes());
+        fmap.extend_from_slice(&(len as u32).to_le_bytes());
+        fmap.extend_from_slice(&[0; 32]);
+        fmap.extend_from_slice(&(areas.len() as u16).to_le_bytes());
+        for (name, offset, size) in areas {
+            fmap.extend_from_slice(&offset.to_le_bytes());
+            fmap.extend_from_slice(&size.to_le_bytes());
+            let mut name_bytes = [0; 32];
+            name_bytes[..name.len()].copy_from_slice(name.as_bytes());
+            fmap.extend_from_slice(&name_bytes);
+            fmap.extend_from_slice(&0u16.to_le_bytes());
+        }
+        data[..fmap.len()].copy_from_slice(&fmap);
+        data
+    }
+
+    #[test]
+    fn preserve() {
+        let old = image(0x4000, 0xAA, &[
+            ("SMMSTORE", 0x1000, 0x1000),
+            ("RW_MRC_CACHE", 0x2000, 0x1000),
+            ("OLD_ONLY", 0x3000, 0x100),
+            ("TOO_BIG", 0x3F00, 0x200),
+        ]);
+        let mut new = image(0x4000, 0x55, &[
+            ("SMMSTORE", 0x2000, 0x1000),
+            ("RW_MRC_CACHE", 0x3000, 0x800),
+            ("NEW_ONLY", 0x1000, 0x100),
+            ("TOO_BIG", 0x3F00, 0x200),
+        ]);
+        let results = preserve_fmap_areas(&old, &mut new, &[
+            "SMMSTORE",
+            "RW_MRC_CACHE",
+            "OLD_ONLY",
+            "NEW_ONLY",
+            "MISSING",
+            "TOO_BIG",
+        ]);
+        assert_eq!(results, [
+            AreaCopyResult::Copied { old_offset: 0x1000, new_offset: 0x2000, size: 0x1000 },
+            AreaCopyResult::SizeMismatch { old: 0x1000, new: 0x800 },
+            AreaCopyResult::AbsentInNew,
+            AreaCopyResult::AbsentInOld,
+            AreaCopyResult::AbsentInBoth,
+            AreaCopyResult::OutOfBounds,
+        ]);
+        assert!(new[0x2000..0x3000].iter().all(|&b| b == 0xAA));
+        assert!(new[0x3000..0x4000].iter().all(|&b| b == 0x55));
+    }
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: MIT
+
+use super::PCI_IDS;
+
+/// SPI controller generation, detected from the PCI ID of the controller.
+///
+/// All supported generations share the register layout of [`SpiRegs`](crate::SpiRegs). A
+/// generation with a different layout must be added here before its IDs are added to
+/// [`PCI_IDS`](crate::PCI_IDS), which maps each controller to its generation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Generation {
+    /// Cannon Lake and Comet Lake
+    CannonLake,
+    /// Tiger Lake
+    TigerLake,
+    /// Alder Lake
+    AlderLake,
+    /// Meteor Lake
+    MeteorLake,
+}
+
+impl Generation {
+    /// Generation for a vendor and device ID from [`PCI_IDS`], if it is a supported controller
+    pub fn from_pci_id(vendor_id: u16, device_id: u16) -> Option<Self> {
+        PCI_IDS.iter()
+            .find(|&&(vendor, device, _)| vendor == vendor_id && device == device_id)
+            .map(|&(_, _, generation)| generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PCI_IDS;
+    use super::Generation;
+
+    #[test]
+    fn pci_ids() {
+        for (i, &(vendor_id, device_id, _)) in PCI_IDS.iter().enumerate() {
+            assert!(
+                ! PCI_IDS[..i].iter().any(|&(vendor, device, _)| vendor == vendor_id && device == device_id),
+                "{:04X}:{:04X} is listed twice", vendor_id, device_id
+            );
+        }
+        assert_eq!(Generation::from_pci_id(0x8086, 0xA0A4), Some(Generation::TigerLake));
+        assert_eq!(Generation::from_pci_id(0x1022, 0xA0A4), None);
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: MIT
+
+use super::{FlashAddress, Spi, SpiError};
+
+/// Access that refuses writes and erases in the descriptor and ME regions, which can leave a
+/// device unbootable if they are damaged
+pub struct GuardedSpi<'a, S: Spi> {
+    spi: &'a mut S,
+    protected: [Option<(usize, usize)>; 2],
+    allow_protected: bool,
+}
+
+impl<'a, S: Spi> GuardedSpi<'a, S> {
+    /// Wrap `spi` with the descriptor and ME regions from `base` to `limit`, inclusive, such as
+    /// from [`SpiRegs::region_base_limit`](crate::SpiRegs::region_base_limit).
+    ///
+    /// If `allow_protected` is true, nothing is refused.
+    pub fn new(
+        spi: &'a mut S,
+        descriptor: Option<(usize, usize)>,
+        me: Option<(usize, usize)>,
+        allow_protected: bool,
+    ) -> Self {
+        Self {
+            spi,
+            protected: [descriptor, me],
+            allow_protected,
+        }
+    }
+
+    /// Return [`SpiError::Access`] if `len` bytes at `address` overlap a protected region
+    fn check(&self, address: usize, len: usize) -> Result<(), SpiError> {
+        if self.allow_protected || len == 0 {
+            return Ok(());
+        }
+        let end = address.saturating_add(len - 1);
+        for &(base, limit) in self.protected.iter().flatten() {
+            if address <= limit && end >= base {
+                return Err(SpiError::Access);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: Spi> Spi for GuardedSpi<'a, S> {
+    fn len(&mut self) -> Result<usize, SpiError> {
+        self.spi.len()
+    }
+
+    fn read_at(&mut self, address: FlashAddress, buf: &mut [u8]) -> Result<usize, SpiError> {
+        self.spi.read_at(address, buf)
+    }
+
+    fn erase_at(&mut self, address: FlashAddress) -> Result<(), SpiError> {
+        self.check(address.0 & !0xFFF, 4096)?;
+        self.spi.erase_at(address)
+    }
+
+    fn write_at(&mut self, address: FlashAddress, buf: &[u8]) -> Result<usize, SpiError> {
+        self.check(address.0, buf.len())?;
+        self.spi.write_at(address, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), SpiError> {
+        self.spi.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockSpi;
+    use super::super::{Spi, SpiError};
+    use super::GuardedSpi;
+
+    #[test]
+    fn me_region() {
+        let mut mock = MockSpi::new();
+        let descriptor = Some((0x0000, 0x0FFF));
+        let me = Some((0x1000, 0x7FFF));
+
+        let mut spi = GuardedSpi::new(&mut mock, descriptor, me, false);
+        assert!(matches!(spi.write(0x2000, &[0]), Err(SpiError::Access)));
+        assert!(matches!(spi.write(0x0FFF, &[0; 2]), Err(SpiError::Access)));
+        assert!(matches!(spi.erase(0x7000), Err(SpiError::Access)));
+        assert_eq!(spi.write(0x8000, &[0]).unwrap(), 1);
+        spi.erase(0x8000).unwrap();
+        assert_eq!(mock.flash().data[0x2000], 0xFF);
+
+        let mut spi = GuardedSpi::new(&mut mock, descriptor, me, true);
+        assert_eq!(spi.write(0x2000, &[0]).unwrap(), 1);
+        assert_eq!(mock.flash().data[0x2000], 0);
+    }
+}
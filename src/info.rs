@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: MIT
+
+use super::{LockStatus, RegionKind};
+
+/// Information about the flash, from [`SpiRegs::probe`](crate::SpiRegs::probe).
+///
+/// This is a copy that can be kept after the device is dropped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChipInfo {
+    /// JEDEC ID of component 0: the manufacturer ID followed by two device ID bytes
+    pub jedec_id: [u8; 3],
+    /// Size in bytes, as returned by `len`
+    pub len: usize,
+    /// Number of components in the descriptor
+    pub components: usize,
+    /// Flash descriptor is valid (FDV)
+    pub descriptor_valid: bool,
+    /// Base and limit of each region from FREG, indexed by [`RegionKind::index`]
+    pub regions: [Option<(usize, usize)>; 6],
+    /// Controller lock state
+    pub lock_status: LockStatus,
+}
+
+impl ChipInfo {
+    /// Base and limit of a region, or `None` if the region is unused
+    pub fn region(&self, region: RegionKind) -> Option<(usize, usize)> {
+        self.regions[region.index()]
+    }
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: MIT
+
+use core::fmt;
+
+/// Why [`SpiDev::try_new`](crate::SpiDev::try_new) could not open the controller
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitError {
+    /// MCFG table is too short to hold the ECAM base address
+    MalformedMcfg,
+    /// Mapping or unmapping memory failed, with the message from the mapper
+    Map(&'static str),
+    /// Configuration space of 00:1f.5 is too short
+    ConfigSpace,
+    /// Device at 00:1f.5 is not a supported SPI controller, with the vendor and device ID found
+    /// there, which are all ones if there is no device
+    UnsupportedDevice {
+        vendor_id: u16,
+        device_id: u16,
+    },
+    /// BAR0 of the controller has not been assigned an address
+    BarZero,
+    /// Registers mapped from BAR0 are not aligned
+    Unaligned,
+    /// H_SCIP stayed set when the controller was opened, so a previous cycle may not have
+    /// finished
+    Busy,
+}
+
+impl InitError {
+    /// Message without the details of the error, as returned by
+    /// [`SpiDev::new`](crate::SpiDev::new)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MalformedMcfg => "MCFG is too short",
+            Self::Map(err) => err,
+            Self::ConfigSpace => "config space is too short",
+            Self::UnsupportedDevice { .. } => "no supported SPI device found",
+            Self::BarZero => "SPI BAR0 is not assigned",
+            Self::Unaligned => "SPI registers are not aligned",
+            Self::Busy => "SPI controller busy at init, a previous cycle may not have finished",
+        }
+    }
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnsupportedDevice { vendor_id, device_id } => write!(
+                f,
+                "no supported SPI device found, 00:1f.5 is {:04x}:{:04x}",
+                vendor_id, device_id
+            ),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
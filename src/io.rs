@@ -18,11 +18,18 @@ pub trait Io {
 
     #[inline(always)]
     fn writef(&mut self, flags: Self::Value, value: bool) {
-        let tmp: Self::Value = match value {
-            true => self.read() | flags,
-            false => self.read() & !flags,
-        };
-        self.write(tmp);
+        self.update(|tmp| match value {
+            true => tmp | flags,
+            false => tmp & !flags,
+        });
+    }
+
+    /// Read the value, pass it through `f`, and write back the result. The value is read once,
+    /// but this is not atomic: anything changing it between the read and the write is lost.
+    #[inline(always)]
+    fn update(&mut self, f: impl FnOnce(Self::Value) -> Self::Value) {
+        let value = f(self.read());
+        self.write(value);
     }
 }
 
@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: MIT
+
+use core::fmt;
+
+/// Phase of flashing an image, reported to a [`Logger`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Loading the new image
+    Load,
+    /// Reading the current flash contents
+    Read,
+    /// Erasing and writing the flash
+    Write,
+    /// Reading the flash back to compare with the new image
+    Verify,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Load => "FILE",
+            Self::Read => "READ",
+            Self::Write => "WRITE",
+            Self::Verify => "VERIFY",
+        })
+    }
+}
+
+/// Destination for progress and messages from a tool using this crate, so an embedding
+/// application can show or discard them. The library itself never logs anything.
+pub trait Logger {
+    /// `phase` has reached `done` of `total` bytes
+    fn progress(&mut self, phase: Phase, done: usize, total: usize);
+
+    /// `phase` has finished
+    fn finish(&mut self, phase: Phase);
+
+    /// Anything other than progress
+    fn message(&mut self, args: fmt::Arguments);
+
+    /// Something went wrong, but the tool carries on. Reported as a message by default.
+    fn warn(&mut self, args: fmt::Arguments) {
+        self.message(args);
+    }
+
+    /// Something went wrong that stops the tool, or leaves the flash in a state the user has
+    /// to know about. Reported as a message by default.
+    fn error(&mut self, args: fmt::Arguments) {
+        self.message(args);
+    }
+}
+
+/// Logger that discards everything
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullLogger;
+
+impl Logger for NullLogger {
+    fn progress(&mut self, _phase: Phase, _done: usize, _total: usize) {}
+
+    fn finish(&mut self, _phase: Phase) {}
+
+    fn message(&mut self, _args: fmt::Arguments) {}
+
+    fn warn(&mut self, _args: fmt::Arguments) {}
+
+    fn error(&mut self, _args: fmt::Arguments) {}
+}
@@ -5,20 +5,58 @@ extern crate libc;
 extern crate intel_spi;
 
 use coreboot_fs::Rom;
-use intel_spi::Spi;
-use std::collections::BTreeMap;
-use std::{env, fs, process};
+use intel_spi::{crc32, preserve_fmap_areas, AreaCopyResult, Logger, Master, Phase, RegionKind, SectorAction, Spi, ERASED_BYTE, STATUS_BLOCK_PROTECT};
+use util::StderrLogger;
+use std::{env, fs, io, panic, process};
+use std::io::Read;
 
 #[path = "../examples/util/mod.rs"]
 mod util;
 
-fn copy_region(region: intelflash::RegionKind, old_data: &[u8], new_data: &mut [u8]) -> Result<bool, String> {
+/// Log a message through a [`Logger`]
+macro_rules! info {
+    ($logger:expr, $($arg:tt)*) => {
+        $logger.message(format_args!($($arg)*))
+    };
+}
+
+/// Log a warning through a [`Logger`]
+macro_rules! warn {
+    ($logger:expr, $($arg:tt)*) => {
+        $logger.warn(format_args!($($arg)*))
+    };
+}
+
+/// Log an error through a [`Logger`]
+macro_rules! error {
+    ($logger:expr, $($arg:tt)*) => {
+        $logger.error(format_args!($($arg)*))
+    };
+}
+
+enum RegionCopy {
+    /// Region was copied from the old firmware to the new firmware
+    Copied,
+    /// Neither firmware has the region
+    AbsentInBoth,
+    /// Region is not present on the chip, so nothing can be preserved
+    AbsentOnChip,
+    /// Region sizes differ, so it cannot be copied
+    SizeMismatch { old: usize, new: usize },
+}
+
+fn copy_region(region: intelflash::RegionKind, chip_opt: Option<(usize, usize)>, old_data: &[u8], new_data: &mut [u8]) -> Result<RegionCopy, String> {
     let old_opt = intelflash::Rom::new(old_data)?.get_region_base_limit(region)?;
     let new_opt = intelflash::Rom::new(new_data)?.get_region_base_limit(region)?;
 
     if old_opt.is_none() && new_opt.is_none() {
         // Neither ROM has this region, so ignore it
-        return Ok(false);
+        return Ok(RegionCopy::AbsentInBoth);
+    }
+
+    if chip_opt.is_none() {
+        // The controller does not decode this region, so the old data is not meaningful
+        return Ok(RegionCopy::AbsentOnChip);
     }
 
     let old = match old_opt {
@@ -40,241 +78,423 @@ fn copy_region(region: intelflash::RegionKind, old_data: &[u8], new_data: &mut [
     };
 
     if old.len() != new.len() {
-        return Err(format!("old region size {} does not match new region size {}", old.len(), new.len()));
+        return Ok(RegionCopy::SizeMismatch { old: old.len(), new: new.len() });
     }
 
     new.copy_from_slice(old);
-    Ok(true)
+    Ok(RegionCopy::Copied)
+}
+
+/// Spans to verify after writing the sectors in `written`: each region in `regions` that
+/// contains a written sector, and written sectors that are outside of every region
+fn written_spans(regions: &[(usize, usize)], written: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    for &(base, limit) in written {
+        let span = regions.iter()
+            .find(|&&(region_base, region_limit)| base >= region_base && limit <= region_limit)
+            .copied()
+            .unwrap_or((base, limit));
+        if ! spans.contains(&span) {
+            spans.push(span);
+        }
+    }
+    spans.sort_unstable();
+    spans
+}
+
+/// Compare the flash with `new` in each span from base to limit, inclusive. Only one chunk of
+/// `chunk_size` bytes is held in memory at a time.
+fn verify_spans<S: Spi>(spi: &mut S, new: &[u8], spans: &[(usize, usize)], chunk_size: usize, logger: &mut dyn Logger) {
+    let total = spans.iter().map(|&(base, limit)| limit - base + 1).sum();
+    let mut done = 0;
+    let mut buf = vec![0; chunk_size];
+    for &(base, limit) in spans {
+        let mut address = base;
+        while address <= limit {
+            let len = (limit + 1 - address).min(buf.len());
+            let read = spi.read(address, &mut buf[..len]).unwrap();
+            assert!(read > 0, "\nverification failed at {:#x}: nothing read", address);
+
+            for (i, &byte) in buf[..read].iter().enumerate() {
+                assert!(byte == new[address + i],
+                    "\nverification failed as {:#x}: {:#x} != {:#x}",
+                    address + i,
+                    byte,
+                    new[address + i]
+                );
+            }
+
+            address += read;
+            done += read;
+            logger.progress(Phase::Verify, done, total);
+        }
+    }
+    logger.finish(Phase::Verify);
+}
+
+/// Read `base..=limit` back from the chip, `chunk_size` bytes at a time
+fn read_back<S: Spi>(spi: &mut S, base: usize, limit: usize, chunk_size: usize) -> Result<Vec<u8>, String> {
+    let mut data = vec![0; limit + 1 - base];
+    for (i, chunk) in data.chunks_mut(chunk_size).enumerate() {
+        let address = base + i * chunk_size;
+        spi.read_exact(address, chunk).map_err(|err| format!("read failed at {:#x}: {:?}", address, err))?;
+    }
+    Ok(data)
+}
+
+/// Check that an image looks like firmware rather than an empty or corrupt file
+fn check_image(data: &[u8]) -> Result<(), String> {
+    if data.iter().all(|&b| b == 0x00) {
+        return Err("image is entirely 0x00".to_string());
+    }
+    if data.iter().all(|&b| b == 0xFF) {
+        return Err("image is entirely 0xFF".to_string());
+    }
+    match intelflash::Rom::new(data) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("image does not have a flash descriptor: {}", err)),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("intel-spi [--commit] [--force] [--unprotect] [--no-verify] [--verify-chunk-size size] [--start-offset offset] [--len-override size] [rom file | -]");
+    process::exit(1);
+}
+
+fn parse_offset(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
 }
 
 fn main() {
-    let path = match env::args().nth(1) {
-        Some(some) => some,
-        None => {
-            eprintln!("intel-spi [rom file]");
-            process::exit(1);
+    let mut path_opt = None;
+    let mut commit = env::var("INTEL_SPI_COMMIT").map_or(false, |value| value == "1");
+    let mut force = false;
+    let mut unprotect = false;
+    let mut verify = true;
+    let mut verify_chunk_size = 4096;
+    let mut start_offset = 0;
+    let mut len_override = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--commit" => commit = true,
+            "--force" => force = true,
+            "--unprotect" => unprotect = true,
+            "--no-verify" => verify = false,
+            "--verify-chunk-size" => {
+                verify_chunk_size = match args.next().as_deref().and_then(parse_offset) {
+                    Some(some) if some > 0 => some,
+                    _ => usage(),
+                };
+            },
+            "--start-offset" | "--resume" => {
+                start_offset = match args.next().as_deref().and_then(parse_offset) {
+                    Some(some) => some,
+                    None => usage(),
+                };
+            },
+            "--len-override" => {
+                len_override = match args.next().as_deref().and_then(parse_offset) {
+                    Some(some) => Some(some),
+                    None => usage(),
+                };
+            },
+            _ if arg.starts_with("--") || path_opt.is_some() => usage(),
+            _ => path_opt = Some(arg),
         }
+    }
+
+    let path = match path_opt {
+        Some(some) => some,
+        None => usage(),
     };
 
+    let mut logger = StderrLogger::new();
+
     let mut spi = unsafe { util::get_spi() };
+    spi.set_len_override(len_override);
 
-    eprintln!("SPI HSFSTS_CTL: {:?}", spi.regs.hsfsts_ctl());
+    let (vendor_id, device_id) = spi.pci_id();
+    info!(logger, "SPI: {:04X}:{:04X} rev {:#04X}", vendor_id, device_id, spi.revision());
+    info!(logger, "SPI HSFSTS_CTL: {:?}", spi.regs.hsfsts_ctl());
+
+    if ! spi.regs.is_present().unwrap() {
+        error!(logger, "SPI: no flash detected");
+        process::exit(1);
+    }
 
     // Read new data
     let mut new;
     {
-        let loading = "Loading";
-        eprint!("SPI FILE: {}", loading);
-        new = fs::read(path).unwrap();
-        for _c in loading.chars() {
-            eprint!("\x08 \x08");
+        logger.progress(Phase::Load, 0, 0);
+        new = if path == "-" {
+            // read_to_end only returns at end of input, so a partial image is never used
+            let mut buf = Vec::new();
+            io::stdin().lock().read_to_end(&mut buf).expect("failed to read image from stdin");
+            buf
+        } else {
+            fs::read(path).unwrap()
+        };
+        logger.progress(Phase::Load, new.len(), new.len());
+        logger.finish(Phase::Load);
+    }
+
+    if let Err(err) = check_image(&new) {
+        if force {
+            warn!(logger, "SPI FILE: {}, continuing because of --force", err);
+        } else {
+            error!(logger, "SPI FILE: {}, pass --force to flash it anyway", err);
+            process::exit(1);
         }
-        eprintln!("{} MB", new.len() / (1024 * 1024));
     }
 
-    // Grab new FMAP areas area, if they exist
-    let mut new_areas = BTreeMap::new();
+    // Print new FMAP areas, if they exist
     {
         let rom = Rom::new(&new);
         if let Some(fmap) = rom.fmap() {
             let name: String = fmap.name.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
 
-            eprintln!("  {}", name);
+            info!(logger, "  {}", name);
 
             for i in 0..fmap.nareas {
                 let area = fmap.area(i);
 
                 let name: String = area.name.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
 
-                eprintln!("    {}: {}", i, name);
-
-                new_areas.insert(name, *area);
+                info!(logger, "    {}: {}", i, name);
             }
         }
     }
 
     // Check ROM size
-    let len = spi.len().unwrap();
-    eprintln!("SPI ROM: {} MB", len / (1024 * 1024));
-    assert!(len == new.len(), "firmware.rom size invalid");
+    let mut len = spi.len().unwrap();
+    info!(logger, "SPI ROM: {} MB", len / (1024 * 1024));
+    if len_override.is_some() {
+        // The overridden size is only an upper bound, so just the image is flashed
+        assert!(new.len() <= len, "firmware.rom size invalid");
+        len = new.len();
+    } else {
+        assert!(len == new.len(), "firmware.rom size invalid");
+    }
+
+    let erase_size = 4096;
+    if start_offset % erase_size != 0 || start_offset >= len {
+        error!(logger, "start offset {:#X} must be a multiple of {:#X} within the ROM", start_offset, erase_size);
+        process::exit(1);
+    }
 
     // Read current data
     let mut data;
     {
         data = Vec::with_capacity(len);
-        let mut print_mb = !0; // Invalid number to force first print
         while data.len() < len {
             let mut buf = [0; 4096];
             let read = spi.read(data.len(), &mut buf).unwrap();
             data.extend_from_slice(&buf[..read]);
 
-            // Print output once per megabyte
-            let mb = data.len() / (1024 * 1024);
-            if mb != print_mb {
-                eprint!("\rSPI READ: {} MB", mb);
-                print_mb = mb;
-            }
+            logger.progress(Phase::Read, data.len(), len);
+        }
+        logger.finish(Phase::Read);
+    }
+
+    // Copy old areas to new areas
+    let area_names: &[&str] = &[
+        //Warning: Copying these regions can be dangerous
+        // "RW_MRC_CACHE",
+        // "SMMSTORE",
+    ];
+
+    // Refuse to preserve data if the regions it is copied between have moved or been resized.
+    // The GbE region is only copied if the chip decodes it, and FMAP areas are in the BIOS
+    // region, so it only needs to match if areas are copied.
+    {
+        let mut preserved = Vec::new();
+        if spi.regs.region_base_limit(RegionKind::Ethernet).is_some() {
+            preserved.push(RegionKind::Ethernet);
+        }
+        if ! area_names.is_empty() {
+            preserved.push(RegionKind::Bios);
+        }
+        if let Err(mismatch) = intel_spi::check_region_layout(&data, &new, &preserved) {
+            error!(logger,
+                "{:?}: region is {:X?} on the chip but {:X?} in the new firmware, cannot preserve it",
+                mismatch.region,
+                mismatch.old,
+                mismatch.new
+            );
+            process::exit(1);
         }
-        eprintln!();
     }
 
     // Copy GBE region, if it exists
-    match copy_region(intelflash::RegionKind::Ethernet, &data, &mut new) {
-        Ok(true) => eprintln!("Ethernet: copied region from old firmware to new firmare"),
-        Ok(false) => (),
+    let chip_region = spi.regs.region_base_limit(RegionKind::Ethernet);
+    match copy_region(intelflash::RegionKind::Ethernet, chip_region, &data, &mut new) {
+        Ok(RegionCopy::Copied) => info!(logger, "Ethernet: copied region from old firmware to new firmare"),
+        Ok(RegionCopy::AbsentInBoth) => (),
+        Ok(RegionCopy::AbsentOnChip) => info!(logger, "Ethernet: region not present on chip, not copying"),
+        Ok(RegionCopy::SizeMismatch { old, new }) => {
+            error!(logger, "Ethernet: old region size {} does not match new region size {}", old, new);
+            process::exit(1);
+        },
         Err(err) => panic!("Ethernet: failed to copy: {}", err),
     }
 
-    // Grab old FMAP areas, if they exist
-    let mut areas = BTreeMap::new();
+    // Print old FMAP areas, if they exist
     {
         let rom = Rom::new(&data);
         if let Some(fmap) = rom.fmap() {
             let name: String = fmap.name.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
 
-            eprintln!("  {}", name);
+            info!(logger, "  {}", name);
 
             for i in 0..fmap.nareas {
                 let area = fmap.area(i);
 
                 let name: String = area.name.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
 
-                eprintln!("    {}: {}", i, name);
-
-                areas.insert(name, *area);
+                info!(logger, "    {}: {}", i, name);
             }
         }
     }
 
-    // Copy old areas to new areas
-    let area_names: &[String] = &[
-        //Warning: Copying these regions can be dangerous
-        // "RW_MRC_CACHE".to_string(),
-        // "SMMSTORE".to_string(),
-    ];
-    for area_name in area_names {
-        if let Some(new_area) = new_areas.get(area_name) {
-            let new_offset = new_area.offset as usize;
-            let new_size = new_area.size as usize;
-            eprintln!(
-                "{}: found in new firmware: offset {:#X}, size {} KB",
+    for (area_name, result) in area_names.iter().zip(preserve_fmap_areas(&data, &mut new, area_names)) {
+        match result {
+            AreaCopyResult::Copied { old_offset, new_offset, size } => info!(logger,
+                "{}: copied {} KB from old firmware offset {:#X} to new firmware offset {:#X}",
                 area_name,
-                new_offset,
-                new_size / 1024
-            );
-            let new_slice = new.get_mut(
-                new_offset .. new_offset + new_size
-            ).unwrap();
-
-            if let Some(area) = areas.get(area_name) {
-                let offset = area.offset as usize;
-                let size = area.size as usize;
-                eprintln!(
-                    "{}: found in old firmware: offset {:#X}, size {} KB",
-                    area_name,
-                    new_offset,
-                    new_size / 1024
-                );
-                let slice = data.get(
-                    offset .. offset + size
-                ).unwrap();
-
-                if slice.len() == new_slice.len() {
-                    new_slice.copy_from_slice(slice);
-
-                    eprintln!(
-                        "{}: copied from old firmware to new firmware",
-                        area_name
-                    );
-                } else {
-                    eprintln!(
-                        "{}: old firmware size {} does not match new firmware size {}, not copying",
-                        area_name,
-                        slice.len(),
-                        new_slice.len()
-                    );
-                }
-            } else {
-                eprintln!(
-                    "{}: found in new firmware, but not found in old firmware",
-                    area_name
-                );
-            }
-        } else if areas.get(area_name).is_some() {
-            eprintln!(
+                size / 1024,
+                old_offset,
+                new_offset
+            ),
+            AreaCopyResult::AbsentInBoth => (),
+            AreaCopyResult::AbsentInOld => info!(logger,
+                "{}: found in new firmware, but not found in old firmware",
+                area_name
+            ),
+            AreaCopyResult::AbsentInNew => info!(logger,
                 "{}: found in old firmware, but not found in new firmware",
                 area_name
-            );
+            ),
+            AreaCopyResult::SizeMismatch { old, new } => info!(logger,
+                "{}: old firmware size {} does not match new firmware size {}, not copying",
+                area_name,
+                old,
+                new
+            ),
+            AreaCopyResult::OutOfBounds => info!(logger,
+                "{}: area extends past the end of the firmware, not copying",
+                area_name
+            ),
         }
     }
 
-    // Erase and write
-    {
-        let erase_byte = 0xFF;
-        let erase_size = 4096;
-        let mut i = 0;
-        let mut print_mb = !0; // Invalid number to force first print
-        for (chunk, new_chunk) in data.chunks(erase_size).zip(new.chunks(erase_size)) {
-            // Data matches, meaning sector can be skipped
-            let mut matching = true;
-            // Data is erased, meaning sector can be erased instead of written
-            let mut erased = true;
-            for (&byte, &new_byte) in chunk.iter().zip(new_chunk.iter()) {
-                if new_byte != byte {
-                    matching = false;
-                }
-                if new_byte != erase_byte {
-                    erased = false;
-                }
-            }
-
-            if ! matching {
-                spi.erase(i).unwrap();
-                if ! erased {
-                    spi.write(i, new_chunk).unwrap();
-                }
+    // Without --commit, only report what would change
+    if ! commit {
+        let plan = intel_spi::plan_flash(&data, &new, erase_size, ERASED_BYTE);
+        info!(logger,
+            "SPI DRY RUN: {} of {} sectors would change, {} erased and {} rewritten",
+            plan.sectors.changed(),
+            plan.sectors.total(),
+            plan.sectors.erase,
+            plan.sectors.erase_write
+        );
+        for &region in RegionKind::ALL.iter() {
+            if let Some(counts) = plan.region(region) {
+                info!(logger,
+                    "  {:?}: {} of {} sectors would change",
+                    region,
+                    counts.changed(),
+                    counts.total()
+                );
             }
+        }
+        if start_offset > 0 {
+            info!(logger, "  sectors before {:#X} would be skipped", start_offset);
+        }
+        info!(logger, "Pass --commit or set INTEL_SPI_COMMIT=1 to erase and write the flash");
+        return;
+    }
 
-            i += chunk.len();
-
-            // Print output once per megabyte
-            let mb = i / (1024 * 1024);
-            if mb != print_mb {
-                eprint!("\rSPI WRITE: {} MB", mb);
-                print_mb = mb;
-            }
+    // Clear block protection in the flash status register while writing, if asked to
+    let mut protected_status = None;
+    if unprotect {
+        if spi.regs.lock_status().write_status_disabled {
+            error!(logger, "SPI STATUS: status register writes are disabled by WRSDIS, cannot unprotect");
+            process::exit(1);
+        }
+        let status = spi.regs.unprotect().unwrap();
+        if status & STATUS_BLOCK_PROTECT != 0 {
+            info!(logger, "SPI STATUS: {:#04X}, block protection cleared", status);
+            protected_status = Some(status);
         }
-        eprintln!();
     }
 
-    // Verify
-    {
-        data.clear();
-        let mut print_mb = !0; // Invalid number to force first print
-        while data.len() < len {
-            let mut address = data.len();
+    // Catch a failed erase, write, or verify so that block protection is still restored
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        // Erase and write
+        let mut written = Vec::new();
+        {
+            if start_offset > 0 {
+                info!(logger, "SPI WRITE: starting at {:#X}", start_offset);
+            }
+            for plan in intel_spi::sector_diff(&data, &new, erase_size, ERASED_BYTE).skip(start_offset / erase_size) {
+                if plan.action != SectorAction::Skip {
+                    written.push((plan.address, plan.address + plan.len - 1));
+                }
+                match plan.action {
+                    SectorAction::Skip => (),
+                    SectorAction::Erase => {
+                        spi.erase(plan.address).unwrap();
+                    },
+                    SectorAction::EraseWrite => {
+                        spi.erase(plan.address).unwrap();
+                        spi.write(plan.address, &new[plan.address..plan.address + plan.len]).unwrap();
+                    },
+                }
 
-            let mut buf = [0; 4096];
-            let read = spi.read(address, &mut buf).unwrap();
-            data.extend_from_slice(&buf[..read]);
+                logger.progress(Phase::Write, plan.address + plan.len, len);
+            }
+            logger.finish(Phase::Write);
+        }
 
-            while address < data.len() {
-                assert!(data[address] == new[address],
-                    "\nverification failed as {:#x}: {:#x} != {:#x}",
-                    address,
-                    data[address],
-                    new[address]
-                );
+        // Verify the regions that were written
+        if verify {
+            let regions: Vec<(usize, usize)> = RegionKind::ALL.iter()
+                .filter_map(|&region| spi.regs.region_base_limit(region))
+                .filter(|&(_, limit)| limit < len)
+                .collect();
+            let spans = written_spans(&regions, &written);
+            verify_spans(&mut spi, &new, &spans, verify_chunk_size, &mut logger);
+        }
+    }));
 
-                address += 1;
-            }
+    if let Some(status) = protected_status {
+        match spi.regs.write_status(status) {
+            Ok(()) => info!(logger, "SPI STATUS: {:#04X}, block protection restored", status),
+            Err(err) => error!(logger, "SPI STATUS: failed to restore {:#04X}: {:?}", status, err),
+        }
+    }
+    if let Err(err) = result {
+        panic::resume_unwind(err);
+    }
 
-            let mb = data.len() / (1024 * 1024);
-            if mb != print_mb {
-                eprint!("\rSPI VERIFY: {} MB", mb);
-                print_mb = mb;
+    // Fingerprint each region as it now is on the chip, from a final read. Regions that the
+    // descriptor does not let the host read, such as a locked ME region, are skipped.
+    let access = spi.regs.master_access(Master::Host);
+    for &region in &[RegionKind::Descriptor, RegionKind::Bios, RegionKind::Me, RegionKind::Ethernet] {
+        if ! access.can_read(region) {
+            continue;
+        }
+        if let Some((base, limit)) = spi.regs.region_base_limit(region) {
+            if limit < len {
+                match read_back(&mut spi, base, limit, verify_chunk_size) {
+                    Ok(chip) => info!(logger, "SPI CRC32: {:?}: {:08X}", region, crc32(&chip)),
+                    Err(err) => warn!(logger, "SPI CRC32: {:?}: {}", region, err),
+                }
             }
         }
-        eprintln!();
     }
 }
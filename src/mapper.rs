@@ -11,8 +11,14 @@ pub trait Mapper {
     unsafe fn unmap_aligned(&mut self, address: VirtualAddress, size: usize) -> Result<(), &'static str>;
     fn page_size(&self) -> usize;
 
+    /// Map `size` bytes at `address`, which do not need to be page aligned. The whole pages
+    /// covering them are mapped, and the returned address has the same offset into its page as
+    /// `address`. The same address and size must be passed to [`unmap`](Self::unmap).
     unsafe fn map(&mut self, address: PhysicalAddress, size: usize) -> Result<VirtualAddress, &'static str> {
         let page_size = self.page_size();
+        if address.0 % page_size == 0 && size % page_size == 0 {
+            return self.map_aligned(address, size);
+        }
         let page = address.0/page_size;
         let aligned_address = PhysicalAddress(page * page_size);
         let offset = address.0 - aligned_address.0;
@@ -22,8 +28,12 @@ pub trait Mapper {
         Ok(VirtualAddress(virtual_address.0 + offset))
     }
 
+    /// Unmap a mapping from [`map`](Self::map)
     unsafe fn unmap(&mut self, address: VirtualAddress, size: usize) -> Result<(), &'static str> {
         let page_size = self.page_size();
+        if address.0 % page_size == 0 && size % page_size == 0 {
+            return self.unmap_aligned(address, size);
+        }
         let page = address.0/page_size;
         let aligned_address = VirtualAddress(page * page_size);
         let offset = address.0 - aligned_address.0;
@@ -32,3 +42,260 @@ pub trait Mapper {
         self.unmap_aligned(aligned_address, aligned_size)
     }
 }
+
+/// Mapper that keeps mappings made through it until it is dropped, so that mapping the same
+/// address and size again does not map it again. Up to eight mappings are kept, after which
+/// mappings are passed through to the inner mapper.
+pub struct CachingMapper<M: Mapper> {
+    inner: M,
+    cache: [Option<(PhysicalAddress, usize, VirtualAddress)>; 8],
+}
+
+impl<M: Mapper> CachingMapper<M> {
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            cache: [None; 8],
+        }
+    }
+}
+
+impl<M: Mapper> Mapper for CachingMapper<M> {
+    unsafe fn map_aligned(&mut self, address: PhysicalAddress, size: usize) -> Result<VirtualAddress, &'static str> {
+        for &(cached_address, cached_size, virtual_address) in self.cache.iter().flatten() {
+            if cached_address == address && cached_size == size {
+                return Ok(virtual_address);
+            }
+        }
+
+        let virtual_address = self.inner.map_aligned(address, size)?;
+        if let Some(entry) = self.cache.iter_mut().find(|entry| entry.is_none()) {
+            *entry = Some((address, size, virtual_address));
+        }
+        Ok(virtual_address)
+    }
+
+    unsafe fn unmap_aligned(&mut self, address: VirtualAddress, size: usize) -> Result<(), &'static str> {
+        for &(_, cached_size, virtual_address) in self.cache.iter().flatten() {
+            if virtual_address == address && cached_size == size {
+                // Unmapped when dropped
+                return Ok(());
+            }
+        }
+
+        self.inner.unmap_aligned(address, size)
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+}
+
+impl<M: Mapper> Drop for CachingMapper<M> {
+    fn drop(&mut self) {
+        for (_, size, virtual_address) in self.cache.iter_mut().filter_map(|entry| entry.take()) {
+            let _ = unsafe { self.inner.unmap_aligned(virtual_address, size) };
+        }
+    }
+}
+
+/// Mapper that counts mappings that have not been unmapped, and the bytes they cover, to catch
+/// leaked mappings and mismatched sizes. Dropping it with mappings outstanding panics, unless
+/// the thread is already panicking, where a second panic would abort.
+///
+/// Counting only happens in debug builds, and otherwise mappings are passed straight through.
+pub struct TrackingMapper<M: Mapper> {
+    inner: M,
+    #[cfg(debug_assertions)]
+    maps: usize,
+    #[cfg(debug_assertions)]
+    bytes: usize,
+}
+
+impl<M: Mapper> TrackingMapper<M> {
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            #[cfg(debug_assertions)]
+            maps: 0,
+            #[cfg(debug_assertions)]
+            bytes: 0,
+        }
+    }
+
+    /// Number of mappings that have not been unmapped, or `None` in release builds
+    pub fn outstanding_maps(&self) -> Option<usize> {
+        #[cfg(debug_assertions)]
+        return Some(self.maps);
+        #[cfg(not(debug_assertions))]
+        return None;
+    }
+}
+
+impl<M: Mapper> Mapper for TrackingMapper<M> {
+    unsafe fn map_aligned(&mut self, address: PhysicalAddress, size: usize) -> Result<VirtualAddress, &'static str> {
+        let virtual_address = self.inner.map_aligned(address, size)?;
+        #[cfg(debug_assertions)]
+        {
+            self.maps += 1;
+            self.bytes += size;
+        }
+        Ok(virtual_address)
+    }
+
+    unsafe fn unmap_aligned(&mut self, address: VirtualAddress, size: usize) -> Result<(), &'static str> {
+        self.inner.unmap_aligned(address, size)?;
+        #[cfg(debug_assertions)]
+        {
+            self.maps = self.maps.checked_sub(1).expect("unmapped more mappings than were mapped");
+            self.bytes = self.bytes.checked_sub(size).expect("unmapped more bytes than were mapped");
+        }
+        Ok(())
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+}
+
+impl<M: Mapper> Drop for TrackingMapper<M> {
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, any(test, feature = "std")))]
+        if std::thread::panicking() {
+            return;
+        }
+        #[cfg(debug_assertions)]
+        assert!(
+            self.maps == 0 && self.bytes == 0,
+            "mappings were not unmapped: {} outstanding, covering {} bytes", self.maps, self.bytes
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachingMapper, Mapper, PhysicalAddress, TrackingMapper, VirtualAddress};
+
+    struct CountingMapper<'a> {
+        maps: &'a mut usize,
+        unmaps: &'a mut usize,
+    }
+
+    impl<'a> Mapper for CountingMapper<'a> {
+        unsafe fn map_aligned(&mut self, address: PhysicalAddress, _size: usize) -> Result<VirtualAddress, &'static str> {
+            *self.maps += 1;
+            Ok(VirtualAddress(address.0 + 0x1000_0000))
+        }
+
+        unsafe fn unmap_aligned(&mut self, _address: VirtualAddress, _size: usize) -> Result<(), &'static str> {
+            *self.unmaps += 1;
+            Ok(())
+        }
+
+        fn page_size(&self) -> usize {
+            4096
+        }
+    }
+
+    #[test]
+    fn caching() {
+        let (mut maps, mut unmaps) = (0, 0);
+        {
+            let mut mapper = CachingMapper::new(CountingMapper {
+                maps: &mut maps,
+                unmaps: &mut unmaps,
+            });
+            for _ in 0..4 {
+                unsafe {
+                    let virt = mapper.map(PhysicalAddress(0xFE01_0010), 0x100).unwrap();
+                    assert_eq!(virt, VirtualAddress(0x1000_0000 + 0xFE01_0010));
+                    mapper.unmap(virt, 0x100).unwrap();
+                }
+            }
+            assert_eq!((*mapper.inner.maps, *mapper.inner.unmaps), (1, 0));
+        }
+        assert_eq!((maps, unmaps), (1, 1));
+    }
+
+    #[test]
+    fn alignment() {
+        struct RecordingMapper(Option<(PhysicalAddress, usize)>);
+
+        impl Mapper for RecordingMapper {
+            unsafe fn map_aligned(&mut self, address: PhysicalAddress, size: usize) -> Result<VirtualAddress, &'static str> {
+                self.0 = Some((address, size));
+                Ok(VirtualAddress(address.0 + 0x1000_0000))
+            }
+
+            unsafe fn unmap_aligned(&mut self, _address: VirtualAddress, _size: usize) -> Result<(), &'static str> {
+                Ok(())
+            }
+
+            fn page_size(&self) -> usize {
+                4096
+            }
+        }
+
+        let mut mapper = RecordingMapper(None);
+        for &(address, size, aligned) in &[
+            // Aligned
+            (0xFE01_0000, 0x2000, (0xFE01_0000, 0x2000)),
+            // Offset into the page
+            (0xFE01_0010, 0x1000, (0xFE01_0000, 0x2000)),
+            // Partial page
+            (0xFE01_0000, 0x100, (0xFE01_0000, 0x1000)),
+        ] {
+            let virt = unsafe { mapper.map(PhysicalAddress(address), size).unwrap() };
+            assert_eq!(virt, VirtualAddress(address + 0x1000_0000));
+            assert_eq!(mapper.0, Some((PhysicalAddress(aligned.0), aligned.1)));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn tracking() {
+        let (mut maps, mut unmaps) = (0, 0);
+        let mut mapper = TrackingMapper::new(CountingMapper {
+            maps: &mut maps,
+            unmaps: &mut unmaps,
+        });
+        unsafe {
+            let a = mapper.map(PhysicalAddress(0xFE01_0010), 0x100).unwrap();
+            let b = mapper.map(PhysicalAddress(0xFE02_0000), 0x2000).unwrap();
+            assert_eq!(mapper.outstanding_maps(), Some(2));
+            mapper.unmap(a, 0x100).unwrap();
+            mapper.unmap(b, 0x2000).unwrap();
+        }
+        assert_eq!(mapper.outstanding_maps(), Some(0));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "0 outstanding, covering 4096 bytes")]
+    fn tracking_mismatch() {
+        let (mut maps, mut unmaps) = (0, 0);
+        let mut mapper = TrackingMapper::new(CountingMapper {
+            maps: &mut maps,
+            unmaps: &mut unmaps,
+        });
+        unsafe {
+            let virt = mapper.map(PhysicalAddress(0xFE01_0000), 0x2000).unwrap();
+            // Only the first page is unmapped
+            mapper.unmap(virt, 0x1000).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "unwinding")]
+    fn tracking_unwind() {
+        let (mut maps, mut unmaps) = (0, 0);
+        let mut mapper = TrackingMapper::new(CountingMapper {
+            maps: &mut maps,
+            unmaps: &mut unmaps,
+        });
+        unsafe { mapper.map(PhysicalAddress(0xFE01_0000), 0x1000).unwrap() };
+        // Dropped during the unwind with a mapping outstanding, which must not abort
+        panic!("unwinding");
+    }
+}
@@ -10,6 +10,18 @@ pub struct Mmio<T> {
     value: T
 }
 
+impl<T> Mmio<T> {
+    /// Raw pointer to the register, for computing offsets or handing off to other code
+    pub fn as_ptr(&self) -> *const T {
+        &self.value
+    }
+
+    /// Raw mutable pointer to the register, accesses through it must remain volatile
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        &mut self.value
+    }
+}
+
 impl<T> Io for Mmio<T> where T: Copy + PartialEq + BitAnd<Output = T> + BitOr<Output = T> + Not<Output = T> {
     type Value = T;
 
@@ -21,3 +33,21 @@ impl<T> Io for Mmio<T> where T: Copy + PartialEq + BitAnd<Output = T> + BitOr<Ou
         unsafe { ptr::write_volatile(&mut self.value, value) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::io::Io;
+    use super::Mmio;
+
+    #[test]
+    fn update() {
+        let mut value = 0x0000_1234_u32;
+        // Safe because Mmio is a transparent wrapper of its value
+        let mmio = unsafe { &mut *(&mut value as *mut u32 as *mut Mmio<u32>) };
+        mmio.update(|value| value << 4 | 0x5);
+        assert_eq!(mmio.read(), 0x0001_2345);
+        mmio.writef(0x0001_0000, false);
+        mmio.writef(0x8000_0000, true);
+        assert_eq!(value, 0x8000_2345);
+    }
+}
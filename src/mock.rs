@@ -0,0 +1,285 @@
+// SPDX-License-Identifier: MIT
+
+use core::cell::{RefCell, RefMut};
+use core::{cmp, mem, ops, ptr};
+use std::boxed::Box;
+use std::rc::Rc;
+use std::vec;
+use std::vec::Vec;
+
+use super::{ComponentDensity, FlashAddress, HsfStsCtl, HsfStsCtlCycle, Io, Sequencer, Spi, SpiError, SpiRegs, DEFAULT_PAGE_SIZE, ERASED_BYTE, FADDR_MASK};
+
+/// Flash behind a [`MockRegs`] bank, with 4 KiB block and 64 KiB sector erases and writes that
+/// can only clear bits
+pub struct MockFlash {
+    pub data: Vec<u8>,
+    /// Bits at an address that are left cleared by an erase
+    pub stuck: Option<(usize, u8)>,
+    /// Status register 1
+    pub status: u8,
+    pub jedec_id: [u8; 3],
+    /// SFDP of the part, which reads as erased past its end
+    pub sfdp: Vec<u8>,
+    /// Cycle and address of every cycle started with FGO so far
+    pub cycles: Vec<(HsfStsCtlCycle, usize)>,
+}
+
+impl MockFlash {
+    /// Number of cycles of the given kind started so far
+    pub fn count(&self, cycle: HsfStsCtlCycle) -> usize {
+        self.cycles.iter().filter(|&&(started, _)| started == cycle).count()
+    }
+
+    /// Run one cycle against the flash, returning false if it fails with FCERR
+    fn run(&mut self, cycle: HsfStsCtlCycle, address: usize, fdata: &mut [u32; 16], count: usize) -> bool {
+        let mut buf = [0; 64];
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = (fdata[i / 4] >> (i % 4 * 8)) as u8;
+        }
+
+        match cycle {
+            HsfStsCtlCycle::Read => match self.data.get(address..address + count) {
+                Some(data) => buf[..count].copy_from_slice(data),
+                None => return false,
+            },
+            HsfStsCtlCycle::Write => match self.data.get_mut(address..address + count) {
+                Some(data) => for (byte, new_byte) in data.iter_mut().zip(buf.iter()) {
+                    *byte &= *new_byte;
+                },
+                None => return false,
+            },
+            HsfStsCtlCycle::BlockErase | HsfStsCtlCycle::SectorErase => {
+                let size = if cycle == HsfStsCtlCycle::SectorErase { 65536 } else { 4096 };
+                let base = address & !(size - 1);
+                match self.data.get_mut(base..base + size) {
+                    Some(data) => data.fill(ERASED_BYTE),
+                    None => return false,
+                }
+                if let Some((stuck_address, bits)) = self.stuck {
+                    if stuck_address >= base && stuck_address < base + size {
+                        self.data[stuck_address] &= !bits;
+                    }
+                }
+            },
+            HsfStsCtlCycle::ReadSfdp => for (i, byte) in buf[..count].iter_mut().enumerate() {
+                *byte = self.sfdp.get(address + i).copied().unwrap_or(ERASED_BYTE);
+            },
+            HsfStsCtlCycle::ReadJedec => buf[..3].copy_from_slice(&self.jedec_id),
+            HsfStsCtlCycle::ReadStatus => buf[0] = self.status,
+            HsfStsCtlCycle::WriteStatus => self.status = buf[0],
+            _ => return false,
+        }
+
+        for (i, dword) in fdata.iter_mut().enumerate() {
+            *dword = u32::from_le_bytes([buf[i * 4], buf[i * 4 + 1], buf[i * 4 + 2], buf[i * 4 + 3]]);
+        }
+        true
+    }
+}
+
+/// Sequencer of a [`MockRegs`] bank, which runs each cycle against its [`MockFlash`] as soon as
+/// it is started.
+///
+/// Memory cannot emulate FDONE being cleared by writing it, so instead of acting as the
+/// controller alongside [`SpiRegs`], the cycle runs when FGO is written, and finishes with
+/// FDONE or FCERR set and FGO clear.
+pub struct MockController {
+    flash: Rc<RefCell<MockFlash>>,
+}
+
+impl Sequencer for MockController {
+    fn started(regs: &mut SpiRegs<Self>) {
+        let mut hsfsts_ctl = regs.hsfsts_ctl();
+        if ! hsfsts_ctl.contains(HsfStsCtl::FGO) {
+            return;
+        }
+        // Writing FGO also wrote back any status bits that were set, clearing them
+        hsfsts_ctl.remove(HsfStsCtl::FDONE | HsfStsCtl::FCERR | HsfStsCtl::H_AEL | HsfStsCtl::FGO);
+
+        let cycle = hsfsts_ctl.cycle();
+        let flash_address = (regs.faddr.read() & FADDR_MASK) as usize;
+        let mut fdata = [0; 16];
+        for (value, register) in fdata.iter_mut().zip(regs.fdata.iter()) {
+            *value = register.read();
+        }
+
+        let flash = regs.sequencer.flash.clone();
+        let mut flash = flash.borrow_mut();
+        flash.cycles.push((cycle, flash_address));
+        if flash.run(cycle, flash_address, &mut fdata, hsfsts_ctl.count() as usize) {
+            for (register, value) in regs.fdata.iter_mut().zip(fdata.iter()) {
+                register.write(*value);
+            }
+            hsfsts_ctl.insert(HsfStsCtl::FDONE);
+        } else {
+            hsfsts_ctl.insert(HsfStsCtl::FCERR);
+        }
+        regs.set_hsfsts_ctl(hsfsts_ctl);
+    }
+}
+
+/// Register bank in memory that runs hardware sequencing cycles against a [`MockFlash`], so
+/// that [`SpiRegs`] can be driven end to end without a controller
+pub struct MockRegs {
+    regs: Box<SpiRegs<MockController>>,
+    pub flash: Rc<RefCell<MockFlash>>,
+}
+
+impl MockRegs {
+    /// Bank with one component holding `data`, whose length must be one of the
+    /// [`ComponentDensity`] sizes. The descriptor override is active and nothing is protected.
+    pub fn new(data: Vec<u8>) -> Self {
+        let density = (data.len() / (512 * 1024)).trailing_zeros();
+        assert_eq!(ComponentDensity::new(density).bytes(), data.len(), "image size is not a component density");
+        Self::with_density(data, density)
+    }
+
+    /// Bank with one component of the density encoded as `density` in the descriptor, holding
+    /// `data`. Cycles past the end of `data` fail.
+    fn with_density(data: Vec<u8>, density: u32) -> Self {
+        let flash = Rc::new(RefCell::new(MockFlash {
+            data,
+            stuck: None,
+            status: 0,
+            jedec_id: [0xEF, 0x40, 0x18],
+            sfdp: Vec::new(),
+            cycles: Vec::new(),
+        }));
+
+        let mut regs: Box<mem::MaybeUninit<SpiRegs<MockController>>> = Box::new(mem::MaybeUninit::zeroed());
+        // Safe because every value is valid for the registers, and the sequencer is written
+        // before the bank is used
+        let mut regs = unsafe {
+            ptr::addr_of_mut!((*regs.as_mut_ptr()).sequencer).write(MockController { flash: flash.clone() });
+            Box::from_raw(Box::into_raw(regs) as *mut SpiRegs<MockController>)
+        };
+        // FDOD reads as this for every FDO index: one component of this density
+        regs.fdod.write(density);
+        Self { regs, flash }
+    }
+}
+
+impl ops::Deref for MockRegs {
+    type Target = SpiRegs<MockController>;
+
+    fn deref(&self) -> &SpiRegs<MockController> {
+        &self.regs
+    }
+}
+
+impl ops::DerefMut for MockRegs {
+    fn deref_mut(&mut self) -> &mut SpiRegs<MockController> {
+        &mut self.regs
+    }
+}
+
+/// Flash of any length behind a [`MockRegs`] bank, accessed through [`SpiRegs`] in 64-byte
+/// cycles like the controller, so that mistakes in driving the FIFO show up in the data.
+///
+/// The bank's descriptor has the smallest component density that holds the image, and accesses
+/// past the end of the image are cut short as for a flash of that length.
+pub struct MockSpi {
+    regs: MockRegs,
+    len: usize,
+}
+
+impl MockSpi {
+    /// Erased flash of 64 KiB
+    pub fn new() -> Self {
+        Self::with_image(vec![ERASED_BYTE; 65536])
+    }
+
+    /// Flash containing `data`, which must fit in the largest component density
+    pub fn with_image(data: Vec<u8>) -> Self {
+        let len = data.len();
+        let density = (cmp::max(len, 1) - 1) / (512 * 1024);
+        let density = usize::BITS - density.leading_zeros();
+        assert!(density <= 0b111, "image is larger than a component");
+        Self {
+            regs: MockRegs::with_density(data, density),
+            len,
+        }
+    }
+
+    /// The emulated flash, to change its contents or inspect the cycles run
+    pub fn flash(&self) -> RefMut<'_, MockFlash> {
+        self.regs.flash.borrow_mut()
+    }
+
+    /// Registers that the flash is accessed through
+    pub fn regs(&mut self) -> &mut MockRegs {
+        &mut self.regs
+    }
+
+    /// Shorten `len` bytes from `address` to the end of the flash
+    fn clamp(&self, address: FlashAddress, len: usize) -> Result<usize, SpiError> {
+        let remaining = self.len.checked_sub(address.0).ok_or(SpiError::OutOfRange)?;
+        Ok(cmp::min(len, remaining))
+    }
+}
+
+impl Default for MockSpi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Spi for MockSpi {
+    fn len(&mut self) -> Result<usize, SpiError> {
+        Ok(self.len)
+    }
+
+    fn read_at(&mut self, address: FlashAddress, buf: &mut [u8]) -> Result<usize, SpiError> {
+        let len = self.clamp(address, buf.len())?;
+        self.regs.read_at(address, &mut buf[..len])
+    }
+
+    fn erase_at(&mut self, address: FlashAddress) -> Result<(), SpiError> {
+        if self.clamp(FlashAddress(address.0 & !0xFFF), 4096)? < 4096 {
+            return Err(SpiError::OutOfRange);
+        }
+        self.regs.erase_at(address)
+    }
+
+    /// Write in cycles that do not cross a 256 byte page
+    fn write_at(&mut self, address: FlashAddress, buf: &[u8]) -> Result<usize, SpiError> {
+        let len = self.clamp(address, buf.len())?;
+        self.regs.write_cancellable(address.0, &buf[..len], DEFAULT_PAGE_SIZE, &|| false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::super::{HsfStsCtlCycle, Spi, SpiError};
+    use super::MockSpi;
+
+    #[test]
+    fn fifo() {
+        let image: Vec<u8> = (0..65536).map(|i| (i * 31 + i / 256) as u8).collect();
+        let mut spi = MockSpi::with_image(image.clone());
+
+        // Every length up to two FIFOs, from an unaligned address, in cycles of up to 64 bytes
+        for len in 1..=128 {
+            let mut buf = vec![0; len];
+            assert_eq!(spi.read(0x1003, &mut buf), Ok(len));
+            assert!(buf[..] == image[0x1003..0x1003 + len]);
+        }
+        assert_eq!(spi.flash().count(HsfStsCtlCycle::Read), (1..=128).map(|len| (len + 63) / 64).sum());
+
+        // Writes stop at each 256 byte page
+        spi.erase(0x2000).unwrap();
+        assert_eq!(spi.write(0x20F0, &image[..100]), Ok(100));
+        assert!(spi.flash().data[0x20F0..0x20F0 + 100] == image[..100]);
+        let writes: Vec<(HsfStsCtlCycle, usize)> = spi.flash().cycles.iter().copied().filter(|&(cycle, _)| cycle == HsfStsCtlCycle::Write).collect();
+        assert_eq!(writes, [(HsfStsCtlCycle::Write, 0x20F0), (HsfStsCtlCycle::Write, 0x2100), (HsfStsCtlCycle::Write, 0x2140)]);
+
+        // Past the end of the image
+        let mut buf = [0; 16];
+        assert_eq!(spi.read(0xFFF8, &mut buf), Ok(8));
+        assert_eq!(spi.read(0x1_0001, &mut buf), Err(SpiError::OutOfRange));
+        assert_eq!(spi.erase(0x1_0000), Err(SpiError::OutOfRange));
+    }
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: MIT
+
+use core::slice;
+
+use super::{Mapper, PhysicalAddress};
+
+/// Copy the 4 KiB configuration space of a PCI device, using the ECAM region at `base`
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn read_config_space<M: Mapper>(mapper: &mut M, base: usize, bus: u8, dev: u8, func: u8) -> Result<[u8; 4096], &'static str> {
+    let size = 4096;
+    let phys = PhysicalAddress(
+        base |
+        ((bus as usize) << 20) |
+        ((dev as usize) << 15) |
+        ((func as usize) << 12)
+    );
+    let virt = mapper.map(phys, size)?;
+    let mut data = [0; 4096];
+    data.copy_from_slice(slice::from_raw_parts(virt.0 as *const u8, size));
+    mapper.unmap(virt, size)?;
+    Ok(data)
+}
+
+/// Decode a little endian `u32` at `offset`, or `None` if `buf` is too short
+pub fn read_u32_le(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Decode a little endian `u64` at `offset`, or `None` if `buf` is too short
+pub fn read_u64_le(buf: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buf.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_u32_le, read_u64_le};
+
+    #[test]
+    fn decode() {
+        let buf = [0x86, 0x80, 0x24, 0x7A, 0x00, 0x00, 0x00, 0xE0, 0x01];
+        assert_eq!(read_u32_le(&buf, 0), Some(0x7A24_8086));
+        assert_eq!(read_u32_le(&buf, 4), Some(0xE000_0000));
+        assert_eq!(read_u64_le(&buf, 0), Some(0xE000_0000_7A24_8086));
+        assert_eq!(read_u64_le(&buf, 1), Some(0x01E0_0000_007A_2480));
+    }
+
+    #[test]
+    fn bounds() {
+        let buf = [0; 8];
+        assert_eq!(read_u32_le(&buf, 5), None);
+        assert_eq!(read_u64_le(&buf, 1), None);
+        assert_eq!(read_u32_le(&buf, usize::MAX), None);
+    }
+}
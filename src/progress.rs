@@ -0,0 +1,37 @@
+// SPDX-License-Identifier: MIT
+
+use super::SpiError;
+
+/// Progress of a read or write, updated after every cycle by
+/// [`SpiRegs::read_with_progress`](crate::SpiRegs::read_with_progress) and
+/// [`SpiRegs::write_with_progress`](crate::SpiRegs::write_with_progress), for callers that
+/// cannot use a closure to track progress
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Progress {
+    /// Bytes completed so far
+    pub done: usize,
+    /// Bytes in the whole operation
+    pub total: usize,
+    /// Error that stopped the operation, if any
+    pub last_error: Option<SpiError>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reset for an operation of `total` bytes
+    pub fn start(&mut self, total: usize) {
+        *self = Self {
+            done: 0,
+            total,
+            last_error: None,
+        };
+    }
+
+    /// Whether every byte has been completed
+    pub fn is_complete(&self) -> bool {
+        self.done >= self.total
+    }
+}
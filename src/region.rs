@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MIT
+
+use core::cmp;
+
+use super::{FlashAddress, Spi, SpiError};
+
+/// Flash regions, numbered as in the FREG registers and the descriptor region section
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(usize)]
+pub enum RegionKind {
+    /// Flash Descriptor
+    Descriptor = 0,
+    /// BIOS
+    Bios = 1,
+    /// Management Engine
+    Me = 2,
+    /// Gigabit Ethernet
+    Ethernet = 3,
+    /// Platform Data
+    PlatformData = 4,
+    /// Device Expansion
+    DeviceExpansion = 5,
+}
+
+impl RegionKind {
+    /// All regions, in index order
+    pub const ALL: [Self; 6] = [
+        Self::Descriptor,
+        Self::Bios,
+        Self::Me,
+        Self::Ethernet,
+        Self::PlatformData,
+        Self::DeviceExpansion,
+    ];
+
+    /// Index of this region in FREG
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Region for an index in FREG, if it is one of the known regions
+    pub fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).copied()
+    }
+}
+
+/// Access to the BIOS region using addresses relative to the start of the region
+pub struct BiosRegion<'a, S: Spi> {
+    spi: &'a mut S,
+    base: usize,
+    limit: usize,
+}
+
+impl<'a, S: Spi> BiosRegion<'a, S> {
+    /// Wrap `spi` for a BIOS region from `base` to `limit`, inclusive, such as from
+    /// [`SpiRegs::bios_base_limit`](crate::SpiRegs::bios_base_limit). Returns `None` if
+    /// `limit` is below `base`, or the region is too large for its length to fit in a `usize`.
+    pub fn new(spi: &'a mut S, base: usize, limit: usize) -> Option<Self> {
+        limit.checked_sub(base)?.checked_add(1)?;
+        Some(Self { spi, base, limit })
+    }
+
+    /// Absolute flash address of the start of the region
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Translate a region offset to a flash address, and the bytes remaining in the region
+    fn translate(&self, address: usize) -> Result<(usize, usize), SpiError> {
+        let len = self.limit - self.base + 1;
+        if address < len {
+            Ok((self.base + address, len - address))
+        } else {
+            Err(SpiError::OutOfRange)
+        }
+    }
+}
+
+impl<'a, S: Spi> Spi for BiosRegion<'a, S> {
+    fn len(&mut self) -> Result<usize, SpiError> {
+        Ok(self.limit - self.base + 1)
+    }
+
+    fn read_at(&mut self, address: FlashAddress, buf: &mut [u8]) -> Result<usize, SpiError> {
+        let (address, remaining) = self.translate(address.0)?;
+        let len = cmp::min(buf.len(), remaining);
+        self.spi.read_at(FlashAddress(address), &mut buf[..len])
+    }
+
+    fn erase_at(&mut self, address: FlashAddress) -> Result<(), SpiError> {
+        let (address, _) = self.translate(address.0)?;
+        self.spi.erase_at(FlashAddress(address))
+    }
+
+    fn write_at(&mut self, address: FlashAddress, buf: &[u8]) -> Result<usize, SpiError> {
+        let (address, remaining) = self.translate(address.0)?;
+        let len = cmp::min(buf.len(), remaining);
+        self.spi.write_at(FlashAddress(address), &buf[..len])
+    }
+
+    fn flush(&mut self) -> Result<(), SpiError> {
+        self.spi.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockSpi;
+    use super::super::{Spi, SpiError};
+    use super::BiosRegion;
+
+    #[test]
+    fn bounds() {
+        let mut mock = MockSpi::new();
+        assert!(BiosRegion::new(&mut mock, 0x2000, 0x1FFF).is_none());
+        assert!(BiosRegion::new(&mut mock, 0, usize::MAX).is_none());
+        // A region of a single byte
+        assert_eq!(BiosRegion::new(&mut mock, 0x2000, 0x2000).unwrap().len(), Ok(1));
+    }
+
+    #[test]
+    fn translate() {
+        let mut mock = MockSpi::new();
+        let mut region = BiosRegion::new(&mut mock, 0x4000, 0x7FFF).unwrap();
+        assert_eq!(region.base(), 0x4000);
+        assert_eq!(region.len(), Ok(0x4000));
+
+        assert_eq!(region.write(0x10, &[0x12, 0x34]), Ok(2));
+        // Writes and reads stop at the limit
+        assert_eq!(region.write(0x3FFF, &[0x56, 0x78]), Ok(1));
+        let mut buf = [0; 4];
+        assert_eq!(region.read(0x3FFE, &mut buf), Ok(2));
+        assert_eq!(buf[..2], [0xFF, 0x56]);
+        assert_eq!(region.read(0x4000, &mut buf), Err(SpiError::OutOfRange));
+        assert_eq!(region.erase(0x4000), Err(SpiError::OutOfRange));
+
+        region.erase(0x0).unwrap();
+        assert_eq!(mock.flash().data[0x4010..0x4012], [0xFF, 0xFF]);
+        assert_eq!(mock.flash().data[0x7FFF], 0x56);
+    }
+}
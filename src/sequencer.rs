@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: MIT
+
+use super::SpiRegs;
+
+/// What runs the hardware sequencing cycles started through a [`SpiRegs`].
+///
+/// [`SpiRegs`] starts a cycle by setting FGO and then polls for FDONE or FCERR. On hardware the
+/// controller runs the cycle by itself, which is [`Controller`]. A register bank in memory has
+/// nothing running cycles, so its sequencer runs each one when it is started instead.
+pub trait Sequencer: Sized {
+    /// Called once a cycle has been started by writing HSFSTS_CTL with FGO set, before FDONE
+    /// and FCERR are polled
+    fn started(regs: &mut SpiRegs<Self>);
+}
+
+/// The SPI controller, which runs cycles without help
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Controller;
+
+impl Sequencer for Controller {
+    #[inline(always)]
+    fn started(_regs: &mut SpiRegs<Self>) {}
+}
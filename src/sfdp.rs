@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: MIT
+
+/// "SFDP" signature at the start of the SFDP, read as a little endian dword
+const SIGNATURE: u32 = 0x5044_4653;
+
+/// Address and length in dwords of the basic flash parameter table, from the first 16 bytes of
+/// the SFDP: the SFDP header followed by the first parameter header, which is always the basic
+/// flash parameter table
+pub(crate) fn basic_table(header: &[u8; 16]) -> Option<(u32, usize)> {
+    if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != SIGNATURE {
+        return None;
+    }
+    // Parameter ID LSB of the basic flash parameter table
+    if header[8] != 0x00 {
+        return None;
+    }
+    let len = header[11] as usize;
+    let pointer = u32::from_le_bytes([header[12], header[13], header[14], 0]);
+    Some((pointer, len))
+}
+
+/// Page size in bytes from the 11th dword of the basic flash parameter table, which is only
+/// present in tables of at least 11 dwords
+pub(crate) fn page_size(dword11: u32) -> usize {
+    1 << ((dword11 >> 4) & 0xF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{basic_table, page_size};
+
+    #[test]
+    fn parse() {
+        let mut header = [0; 16];
+        header[..4].copy_from_slice(b"SFDP");
+        // Revision 1.6, one parameter header
+        header[4..8].copy_from_slice(&[0x06, 0x01, 0x00, 0xFF]);
+        // Basic flash parameter table revision 1.6, 16 dwords at 0x30
+        header[8..16].copy_from_slice(&[0x00, 0x06, 0x01, 0x10, 0x30, 0x00, 0x00, 0xFF]);
+        assert_eq!(basic_table(&header), Some((0x30, 16)));
+
+        header[0] = 0;
+        assert_eq!(basic_table(&header), None);
+
+        // 256 byte pages
+        assert_eq!(page_size(0x0000_0080), 256);
+        assert_eq!(page_size(0x0000_0050), 32);
+    }
+}
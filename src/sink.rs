@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: MIT
+
+use std::{cmp, io};
+use std::vec::Vec;
+
+use super::{Spi, SpiError};
+
+/// Erases and writes data to flash one 4 KiB sector at a time as it is written to the sink.
+///
+/// Data is only written to flash when a sector fills up or when [`flush`](io::Write::flush) is
+/// called, so `flush` must be called after the last write. Flushing a partial sector keeps the
+/// flash contents after the written data, which are read back before the sector is erased.
+pub struct SpiSink<'a, S: Spi> {
+    spi: &'a mut S,
+    address: usize,
+    len: usize,
+    buf: Vec<u8>,
+}
+
+impl<'a, S: Spi> SpiSink<'a, S> {
+    const SECTOR_SIZE: usize = 4096;
+
+    /// Create a sink writing to flash starting at `address`, which must be sector aligned
+    pub fn new(spi: &'a mut S, address: usize) -> Result<Self, SpiError> {
+        if address % Self::SECTOR_SIZE != 0 {
+            return Err(SpiError::OutOfRange);
+        }
+        let len = spi.len()?;
+        Ok(Self {
+            spi,
+            address,
+            len,
+            buf: Vec::with_capacity(Self::SECTOR_SIZE),
+        })
+    }
+
+    /// Absolute flash address that the next byte will be written to
+    pub fn position(&self) -> usize {
+        self.address + self.buf.len()
+    }
+
+    /// Erase the current sector and write the buffered data to it, along with whatever the
+    /// flash held after the buffered data if the sector is partial
+    fn write_sector(&mut self) -> io::Result<()> {
+        let buffered = self.buf.len();
+        let end = cmp::min(self.address + Self::SECTOR_SIZE, self.len);
+        self.buf.resize(end - self.address, 0);
+        let result = rewrite_sector(self.spi, self.address, &mut self.buf, buffered);
+        self.buf.truncate(buffered);
+        result
+    }
+}
+
+/// Fill `sector` after the first `buffered` bytes from the flash, then erase the sector at
+/// `address` and write all of `sector` to it
+fn rewrite_sector<S: Spi>(spi: &mut S, address: usize, sector: &mut [u8], buffered: usize) -> io::Result<()> {
+    spi.read_exact(address + buffered, &mut sector[buffered..]).map_err(io_error)?;
+    spi.erase(address).map_err(io_error)?;
+    let mut count = 0;
+    while count < sector.len() {
+        match spi.write(address + count, &sector[count..]).map_err(io_error)? {
+            0 => return Err(io::Error::new(io::ErrorKind::WriteZero, "flash write made no progress")),
+            written => count += written,
+        }
+    }
+    Ok(())
+}
+
+fn io_error(err: SpiError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, std::format!("{:?}", err))
+}
+
+impl<'a, S: Spi> io::Write for SpiSink<'a, S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position());
+        if remaining == 0 && !data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "end of flash"));
+        }
+
+        let count = cmp::min(data.len(), cmp::min(Self::SECTOR_SIZE - self.buf.len(), remaining));
+        self.buf.extend_from_slice(&data[..count]);
+        if self.buf.len() == Self::SECTOR_SIZE {
+            self.write_sector()?;
+            self.address += Self::SECTOR_SIZE;
+            self.buf.clear();
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // A partial sector is kept, so that further writes rewrite the whole sector
+        if !self.buf.is_empty() {
+            self.write_sector()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, Write};
+    use std::vec::Vec;
+
+    use super::super::mock::MockSpi;
+    use super::super::{FlashAddress, Spi, SpiError};
+    use super::SpiSink;
+
+    /// Flash that writes at most `max_write` bytes at a time
+    struct ShortWrite {
+        spi: MockSpi,
+        max_write: usize,
+    }
+
+    impl Spi for ShortWrite {
+        fn len(&mut self) -> Result<usize, SpiError> {
+            self.spi.len()
+        }
+
+        fn read_at(&mut self, address: FlashAddress, buf: &mut [u8]) -> Result<usize, SpiError> {
+            self.spi.read_at(address, buf)
+        }
+
+        fn erase_at(&mut self, address: FlashAddress) -> Result<(), SpiError> {
+            self.spi.erase_at(address)
+        }
+
+        fn write_at(&mut self, address: FlashAddress, buf: &[u8]) -> Result<usize, SpiError> {
+            let len = cmp::min(buf.len(), self.max_write);
+            self.spi.write_at(address, &buf[..len])
+        }
+    }
+
+    #[test]
+    fn odd_chunks() {
+        let image: Vec<u8> = (0..10000).map(|i| (i * 7 + i / 256) as u8).collect();
+        let mut spi = MockSpi::new();
+        spi.flash().data.fill(0);
+        {
+            let mut sink = SpiSink::new(&mut spi, 0x1000).unwrap();
+            let mut sizes = [1, 13, 4095, 7, 300].iter().cycle();
+            for (i, half) in image.chunks(5000).enumerate() {
+                let mut data = half;
+                while !data.is_empty() {
+                    let size = cmp::min(*sizes.next().unwrap(), data.len());
+                    sink.write_all(&data[..size]).unwrap();
+                    data = &data[size..];
+                }
+                // Flushing a partial sector partway must not lose data
+                sink.flush().unwrap();
+                assert_eq!(sink.position(), 0x1000 + (i + 1) * 5000);
+            }
+        }
+        assert_eq!(&spi.flash().data[0x1000..0x1000 + image.len()], &image[..]);
+        // The rest of the last sector is kept, not erased
+        assert!(spi.flash().data[0x1000 + image.len()..0x4000].iter().all(|&x| x == 0));
+        assert!(spi.flash().data[..0x1000].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn partial_sector() {
+        let mut spi = MockSpi::with_image((0..65536).map(|i| (i / 3) as u8).collect());
+        let old = spi.flash().data.clone();
+        {
+            let mut sink = SpiSink::new(&mut spi, 0x2000).unwrap();
+            sink.write_all(&[0x5A; 0x1100]).unwrap();
+            sink.flush().unwrap();
+        }
+        assert!(spi.flash().data[0x2000..0x3100].iter().all(|&x| x == 0x5A));
+        assert!(spi.flash().data[0x3100..] == old[0x3100..]);
+        assert!(spi.flash().data[..0x2000] == old[..0x2000]);
+    }
+
+    #[test]
+    fn short_writes() {
+        let image: Vec<u8> = (0..0x1800).map(|i| (i * 5) as u8).collect();
+        let mut spi = ShortWrite { spi: MockSpi::new(), max_write: 100 };
+        {
+            let mut sink = SpiSink::new(&mut spi, 0).unwrap();
+            sink.write_all(&image).unwrap();
+            sink.flush().unwrap();
+        }
+        assert!(spi.spi.flash().data[..image.len()] == image[..]);
+
+        // A write that makes no progress fails instead of being dropped
+        spi.max_write = 0;
+        let mut sink = SpiSink::new(&mut spi, 0).unwrap();
+        let err = sink.write_all(&image).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn end_of_flash() {
+        let mut spi = MockSpi::new();
+        let len = spi.flash().data.len();
+        let mut sink = SpiSink::new(&mut spi, len - 0x1000).unwrap();
+        assert_eq!(sink.write(&[0; 0x2000]).unwrap(), 0x1000);
+        assert!(sink.write(&[0]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: MIT
+
+use super::{sector_diff, BiosRegion, SectorAction, SectorCounts, Spi, SpiError, SpiRegs, ERASED_BYTE};
+
+/// Size of the sectors compared, erased, and written by [`update_bios_region`]
+const SECTOR_SIZE: usize = 4096;
+
+/// What [`update_bios_region`] changed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FlashReport {
+    /// Flash address of the start of the BIOS region
+    pub base: usize,
+    /// Length of the BIOS region
+    pub len: usize,
+    /// Sectors that were skipped, only erased, or erased and written
+    pub sectors: SectorCounts,
+}
+
+/// Replace the BIOS region with `new_bios`, leaving the rest of the flash untouched.
+///
+/// The region is taken from BFPREG in `regs`, and `new_bios` must be exactly as long as it,
+/// otherwise [`SpiError::OutOfRange`] is returned before anything is changed. Each 4 KiB sector
+/// is read and compared with `new_bios`, and only sectors that differ are erased and, unless the
+/// new contents are erased, written. Every changed sector is read back, returning
+/// [`SpiError::Mismatch`] with the first flash address that does not match.
+///
+/// For [`SpiDev`](crate::SpiDev), which owns its registers, use
+/// [`SpiDev::update_bios_region`](crate::SpiDev::update_bios_region).
+pub fn update_bios_region<S: Spi>(spi: &mut S, regs: &SpiRegs, new_bios: &[u8]) -> Result<FlashReport, SpiError> {
+    let (base, limit) = regs.bios_base_limit().ok_or(SpiError::Register)?;
+    update_region(spi, base, limit, new_bios)
+}
+
+/// Implementation of [`update_bios_region`] for a region from `base` to `limit`, inclusive
+pub(crate) fn update_region<S: Spi>(spi: &mut S, base: usize, limit: usize, new_bios: &[u8]) -> Result<FlashReport, SpiError> {
+    let mut region = BiosRegion::new(spi, base, limit).ok_or(SpiError::Register)?;
+    let len = region.len()?;
+    if new_bios.len() != len {
+        return Err(SpiError::OutOfRange);
+    }
+
+    let mut sectors = SectorCounts::default();
+    let mut buf = [0; SECTOR_SIZE];
+    for (i, new) in new_bios.chunks(SECTOR_SIZE).enumerate() {
+        let address = i * SECTOR_SIZE;
+        let old = &mut buf[..new.len()];
+        region.read_exact(address, old)?;
+        // The chunk is one sector, so there is exactly one plan
+        let action = match sector_diff(old, new, SECTOR_SIZE, ERASED_BYTE).next() {
+            Some(plan) => plan.action,
+            None => continue,
+        };
+        sectors.add(action);
+        if action == SectorAction::Skip {
+            continue;
+        }
+
+        region.erase(address)?;
+        if action == SectorAction::EraseWrite {
+            let mut count = 0;
+            while count < new.len() {
+                match region.write(address + count, &new[count..])? {
+                    0 => return Err(SpiError::OutOfRange),
+                    written => count += written,
+                }
+            }
+        }
+
+        let old = &mut buf[..new.len()];
+        region.read_exact(address, old)?;
+        if let Some(j) = old.iter().zip(new.iter()).position(|(a, b)| a != b) {
+            return Err(SpiError::Mismatch(base + address + j));
+        }
+    }
+    region.flush()?;
+
+    Ok(FlashReport { base, len, sectors })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+
+    use super::super::{Io, MockSpi, SectorCounts, SpiError, SpiRegs};
+    use super::{update_bios_region, FlashReport};
+
+    #[test]
+    fn update() {
+        let mut regs: SpiRegs = unsafe { mem::zeroed() };
+        // BIOS region from 0x4000 to 0x7FFF
+        regs.bfpreg.write(0x0007_0004);
+
+        let mut spi = MockSpi::new();
+        spi.flash().data[0x0000..0x4000].fill(0x11);
+        spi.flash().data[0x5000..0x7000].fill(0x22);
+        let mut new = [0xFF; 0x4000];
+        new[0x2000..0x3000].fill(0x22);
+        new[0x3000..0x4000].fill(0x33);
+
+        let report = update_bios_region(&mut spi, &regs, &new).unwrap();
+        assert_eq!(report, FlashReport {
+            base: 0x4000,
+            len: 0x4000,
+            sectors: SectorCounts { skip: 2, erase: 1, erase_write: 1 },
+        });
+        assert_eq!(&spi.flash().data[0x4000..0x8000], &new[..]);
+        // Everything outside the region is preserved
+        assert!(spi.flash().data[..0x4000].iter().all(|&byte| byte == 0x11));
+
+        // Nothing changes the second time
+        let report = update_bios_region(&mut spi, &regs, &new).unwrap();
+        assert_eq!(report.sectors.changed(), 0);
+
+        // The blob must match the region
+        assert_eq!(update_bios_region(&mut spi, &regs, &new[..0x3000]), Err(SpiError::OutOfRange));
+
+        // A stuck part fails verification
+        spi.flash().stuck = Some((0x5001, 0x01));
+        new[0x1000] = 0x44;
+        assert_eq!(update_bios_region(&mut spi, &regs, &new), Err(SpiError::Mismatch(0x5001)));
+    }
+}
@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: MIT
+
+/// Vendor Specific Component Capabilities for one flash component
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Vscc {
+    /// Block/Sector Erase Size in bytes, or `None` if the encoding is reserved
+    pub erase_size: Option<usize>,
+    /// Erase Opcode used for `erase_size`
+    pub erase_opcode: u8,
+    /// 64 KiB Erase Opcode Valid
+    pub erase_64k_valid: bool,
+    /// Write Granularity in bytes
+    pub write_granularity: usize,
+    /// Write Status Required before writes
+    pub write_status_required: bool,
+    /// Write Enable opcode sent before writing the status register, from Write Enable on Write
+    /// Status
+    pub write_enable_opcode: u8,
+    /// Vendor Component Lock
+    pub locked: bool,
+    /// Component Property Parameter Table Valid
+    pub parameter_table_valid: bool,
+}
+
+impl Vscc {
+    /// Decode from a raw VSCC value
+    pub fn new(vscc: u32) -> Self {
+        Self {
+            erase_size: match vscc & 0b11 {
+                0b00 => Some(256),
+                0b01 => Some(4096),
+                0b11 => Some(65536),
+                _ => None,
+            },
+            erase_opcode: (vscc >> 8) as u8,
+            erase_64k_valid: vscc & (1 << 29) != 0,
+            write_granularity: if vscc & (1 << 2) != 0 { 64 } else { 1 },
+            write_status_required: vscc & (1 << 3) != 0,
+            write_enable_opcode: if vscc & (1 << 4) != 0 { 0x50 } else { 0x06 },
+            locked: vscc & (1 << 23) != 0,
+            parameter_table_valid: vscc & (1 << 31) != 0,
+        }
+    }
+}